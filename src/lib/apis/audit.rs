@@ -0,0 +1,193 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::OnceLock;
+use std::thread;
+
+use actix_web::HttpRequest;
+use chrono::{Local, SecondsFormat};
+use log::error;
+use serde::Serialize;
+
+use crate::apis::auth::Identity;
+use crate::apis::response::{ApiError, ErrorCode};
+use crate::apis::upload::WriteOutcome;
+use crate::config::{AuditConfig, ServerConfig};
+
+/// Feeds the thread appending entries, so handlers never wait on the disk.
+static AUDIT_WRITER: OnceLock<Sender<(AuditConfig, AuditEntry)>> = OnceLock::new();
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditAction {
+    Upload,
+    Download,
+    Delete,
+    /// A file put back to its previous content, e.g. by a failed batch.
+    Restore,
+}
+
+/// One line of the audit log.
+#[derive(Serialize, Debug, Clone)]
+pub struct AuditEntry {
+    pub time: String,
+    pub action: AuditAction,
+    pub identity: String,
+    pub remote_addr: Option<String>,
+    pub path: String,
+    /// md5 of the file before the request.
+    pub old_hash: Option<String>,
+    /// md5 of the file after the request.
+    pub new_hash: Option<String>,
+    pub bytes: Option<u64>,
+    pub backup: Option<String>,
+    /// `ok`, `unchanged` or `error`.
+    pub result: &'static str,
+    pub code: Option<ErrorCode>,
+    pub error: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(req: &HttpRequest, identity: &Identity, action: AuditAction, path: &str) -> Self {
+        AuditEntry {
+            time: Local::now().to_rfc3339_opts(SecondsFormat::Millis, false),
+            action,
+            identity: identity.name().to_string(),
+            remote_addr: req.peer_addr().map(|addr| addr.ip().to_string()),
+            path: path.to_string(),
+            old_hash: None,
+            new_hash: None,
+            bytes: None,
+            backup: None,
+            result: "ok",
+            code: None,
+            error: None,
+        }
+    }
+
+    /// Take the hashes, size and backup of a write that succeeded.
+    pub fn with_write(mut self, outcome: Option<&WriteOutcome>) -> Self {
+        if let Some(outcome) = outcome {
+            self.old_hash = outcome.old_hash.clone();
+            self.new_hash = Some(outcome.hash.clone());
+            self.bytes = Some(outcome.bytes);
+            self.backup = outcome.backup.clone();
+            if !outcome.changed {
+                self.result = "unchanged";
+            }
+        }
+        self
+    }
+
+    /// Append the entry with the result of the request to `audit.path`, if set.
+    pub fn record<T>(mut self, cfg: &ServerConfig, result: &Result<T, ApiError>) {
+        if let Err(err) = result {
+            self.result = "error";
+            self.code = Some(err.code);
+            self.error = Some(err.message.clone());
+        }
+        self.save(cfg);
+    }
+
+    /// Queue the entry as it is for appending.
+    pub fn save(self, cfg: &ServerConfig) {
+        if cfg.audit.path.is_none() {
+            return;
+        }
+        let writer = AUDIT_WRITER.get_or_init(|| {
+            let (tx, rx) = mpsc::channel();
+            thread::Builder::new()
+                .name("audit".to_string())
+                .spawn(move || write_entries(rx))
+                .expect("spawn audit thread");
+            tx
+        });
+        if let Err(mpsc::SendError((_, entry))) = writer.send((cfg.audit.clone(), self)) {
+            error!("audit thread gone, entry={:?}", entry);
+        }
+    }
+}
+
+/// Append every queued entry to its `audit.path`, reopening the file when a
+/// reload changes it.
+fn write_entries(rx: mpsc::Receiver<(AuditConfig, AuditEntry)>) {
+    let mut current = None;
+    for (cfg, entry) in rx {
+        let Some(path) = cfg.path.as_deref().map(Path::new) else {
+            continue;
+        };
+        if let Err(err) = append(&mut current, &cfg, path, &entry) {
+            error!(
+                "write audit log {} err: {}, entry={:?}",
+                path.display(),
+                err,
+                entry
+            );
+        }
+    }
+}
+
+struct AuditFile {
+    path: PathBuf,
+    file: fs::File,
+    len: u64,
+}
+
+impl AuditFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let len = file.metadata()?.len();
+        Ok(AuditFile {
+            path: path.to_path_buf(),
+            file,
+            len,
+        })
+    }
+}
+
+fn append(
+    current: &mut Option<AuditFile>,
+    cfg: &AuditConfig,
+    path: &Path,
+    entry: &AuditEntry,
+) -> io::Result<()> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+
+    if current.as_ref().is_none_or(|audit| audit.path != path) {
+        *current = Some(AuditFile::open(path)?);
+    }
+    let mut audit = current.take().unwrap();
+    if cfg.max_bytes > 0 && audit.len > 0 && audit.len + line.len() as u64 > cfg.max_bytes {
+        drop(audit);
+        rotate(path, cfg.keep)?;
+        audit = AuditFile::open(path)?;
+    }
+    audit.file.write_all(&line)?;
+    audit.len += line.len() as u64;
+    *current = Some(audit);
+    Ok(())
+}
+
+/// Shift `<path>.N` to `<path>.N+1`, dropping the oldest, and move `path` to `<path>.1`.
+fn rotate(path: &Path, keep: usize) -> io::Result<()> {
+    let rotated = |n: usize| PathBuf::from(format!("{}.{}", path.display(), n));
+    match fs::remove_file(rotated(keep)) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    for n in (1..keep).rev() {
+        match fs::rename(rotated(n), rotated(n + 1)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+    fs::rename(path, rotated(1))
+}
@@ -0,0 +1,113 @@
+use actix_web::{http::header, HttpRequest};
+
+use crate::apis::response::{ApiError, ErrorCode};
+use crate::config::{Access, ServerConfig, TokenConfig};
+
+/// Who sent a request, resolved from its bearer token.
+#[derive(Debug, Clone, Default)]
+pub struct Identity {
+    pub token: Option<TokenConfig>,
+}
+
+impl Identity {
+    pub fn name(&self) -> &str {
+        self.token.as_ref().map_or("anonymous", |t| t.name.as_str())
+    }
+
+    /// The lower of the global and this token's upload limit.
+    pub fn max_upload_bytes(&self, cfg: &ServerConfig) -> Option<u64> {
+        lowest(
+            cfg.limits.max_upload_bytes,
+            self.token.as_ref().and_then(|t| t.max_upload_bytes),
+        )
+    }
+
+    /// The lower of the global and this token's download limit.
+    pub fn max_download_bytes(&self, cfg: &ServerConfig) -> Option<u64> {
+        lowest(
+            cfg.limits.max_download_bytes,
+            self.token.as_ref().and_then(|t| t.max_download_bytes),
+        )
+    }
+
+    /// Check `path` against the roots and this identity's acl.
+    pub fn check(&self, cfg: &ServerConfig, path: &str, access: Access) -> Result<(), ApiError> {
+        if !cfg.in_roots(path) {
+            return Err(ApiError::new(
+                ErrorCode::PermissionDenied,
+                "path is outside of the configured roots",
+            )
+            .with_path(path));
+        }
+
+        let Some(token) = &self.token else {
+            return Ok(());
+        };
+        if token.acl.is_empty()
+            || token.acl.iter().any(|acl| {
+                std::path::Path::new(path).starts_with(&acl.path) && acl.access >= access
+            })
+        {
+            return Ok(());
+        }
+        Err(ApiError::new(
+            ErrorCode::PermissionDenied,
+            format!("token '{}' has no {:?} access", token.name, access).to_lowercase(),
+        )
+        .with_path(path))
+    }
+}
+
+fn lowest(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Resolve the caller. Without configured tokens every request is anonymous.
+pub fn authenticate(req: &HttpRequest, cfg: &ServerConfig) -> Result<Identity, ApiError> {
+    if cfg.tokens.is_empty() {
+        return Ok(Identity::default());
+    }
+
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or_else(|| ApiError::new(ErrorCode::Unauthorized, "missing bearer token"))?;
+    let bearer = bearer.trim().as_bytes();
+    // Every token is compared, so the time taken does not tell which one matched.
+    cfg.tokens
+        .iter()
+        .fold(None, |found, t| {
+            let matches = secret_eq(t.token.as_bytes(), bearer);
+            found.or(matches.then_some(t))
+        })
+        .map(|t| Identity {
+            token: Some(t.clone()),
+        })
+        .ok_or_else(|| ApiError::new(ErrorCode::Unauthorized, "invalid bearer token"))
+}
+
+/// Compare secrets in time that depends only on their lengths, not on where they differ.
+fn secret_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .fold(0u8, |diff, (x, y)| std::hint::black_box(diff | (x ^ y)))
+            == 0
+}
+
+/// Authenticate the request and check one path in a single step.
+pub fn authorize(
+    req: &HttpRequest,
+    cfg: &ServerConfig,
+    path: &str,
+    access: Access,
+) -> Result<Identity, ApiError> {
+    let identity = authenticate(req, cfg)?;
+    identity.check(cfg, path, access)?;
+    Ok(identity)
+}
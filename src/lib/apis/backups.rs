@@ -0,0 +1,68 @@
+use std::path;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::debug;
+use serde::Deserialize;
+
+use crate::apis::auth::authorize;
+use crate::apis::response::{ApiError, ApiResponse};
+use crate::apis::upload::{backup_location, content_md5};
+use crate::config::{Access, SharedConfig};
+
+#[derive(Deserialize)]
+pub struct BackupsReq {
+    file_path: String,
+}
+
+/// The safe mode backups of a file, newest first. The version of a backup is
+/// the `%Y%m%d_%H%M%S` suffix of its path.
+pub async fn list_backups(
+    http_req: HttpRequest,
+    req: web::Json<BackupsReq>,
+    shared: web::Data<SharedConfig>,
+) -> Result<HttpResponse, ApiError> {
+    if req.file_path.is_empty() {
+        return Err(ApiError::invalid("invalid file path"));
+    }
+    let cfg = shared.current();
+    let identity = authorize(&http_req, &cfg, &req.file_path, Access::Read)?;
+    debug!("list backups by {}: {}", identity.name(), req.file_path);
+
+    let target = path::Path::new(&req.file_path);
+    let (dot_backup_dir, _) = backup_location(target).map_err(|e| e.with_path(&req.file_path))?;
+    let filename = target
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let prefix = format!("{}.", filename);
+
+    let mut backups = Vec::new();
+    if let Ok(mut entries) = tokio::fs::read_dir(&dot_backup_dir).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let Some(version) = name.strip_prefix(&prefix) else {
+                continue;
+            };
+            if chrono::NaiveDateTime::parse_from_str(version, "%Y%m%d_%H%M%S").is_err() {
+                continue;
+            }
+            let content = tokio::fs::read(entry.path())
+                .await
+                .map_err(|err| ApiError::io("read backup err", err).with_path(&req.file_path))?;
+            backups.push(ApiResponse {
+                ok: true,
+                path: Some(entry.path().to_string_lossy().to_string()),
+                bytes: Some(content.len() as u64),
+                hash: Some(content_md5(&content)),
+                ..Default::default()
+            });
+        }
+    }
+    backups.sort_by(|a, b| b.path.cmp(&a.path));
+
+    Ok(ApiResponse {
+        files: backups,
+        ..ApiResponse::ok("list backups successfully").with_path(&req.file_path)
+    }
+    .into_http())
+}
@@ -0,0 +1,543 @@
+use actix_multipart::Multipart;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Local;
+use futures::StreamExt;
+use log::{debug, error, warn};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::apis::audit::{AuditAction, AuditEntry};
+use crate::apis::auth::{authenticate, Identity};
+use crate::apis::hooks::{self, HookOutcome, HookStage, HOOK_LOCK};
+use crate::apis::metrics::{Direction, METRICS};
+use crate::apis::response::{ApiError, ApiResponse};
+use crate::apis::upload::{
+    apply_mode, backup_location, content_md5, ensure_free_space, prune_backups,
+    read_content_disposition, safe_create_backup_dir, validate_upload_args, UploadBudget,
+    WriteOutcome,
+};
+use crate::config::{Access, ServerConfig, SharedConfig};
+use crate::util::file::filesystem_id;
+use crate::util::schema::{parse_mode, Action, UploadForm};
+
+static BATCH_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// One file of a batch, from staging until it is committed or rolled back.
+struct Staged {
+    form: UploadForm,
+    /// The target, or the file it links to, which is what gets replaced.
+    real: PathBuf,
+    staged: PathBuf,
+    rollback: PathBuf,
+    unchanged: bool,
+    has_original: bool,
+    committed: bool,
+    /// Dirs staging created, deepest first, removed again if the batch fails.
+    created_dirs: Vec<PathBuf>,
+    outcome: WriteOutcome,
+}
+
+impl Staged {
+    fn new(form: UploadForm, batch_id: &str) -> std::result::Result<Self, ApiError> {
+        let real = real_target(&form.target_file_path)
+            .map_err(|err| err.with_path(&form.target_file_path))?;
+        let staged = PathBuf::from(format!("{}.sync-batch-{}", real.display(), batch_id));
+        let rollback = PathBuf::from(format!("{}.sync-rollback-{}", real.display(), batch_id));
+        Ok(Staged {
+            outcome: WriteOutcome {
+                bytes: form.content.len() as u64,
+                hash: content_md5(&form.content),
+                ..Default::default()
+            },
+            form,
+            real,
+            staged,
+            rollback,
+            unchanged: false,
+            has_original: false,
+            committed: false,
+            created_dirs: Vec::new(),
+        })
+    }
+
+    fn target(&self) -> &Path {
+        &self.real
+    }
+
+    fn error(&self, err: ApiError) -> ApiError {
+        err.with_path(&self.form.target_file_path)
+    }
+}
+
+/// Upload many files at once: every file is staged and validated first, then
+/// all of them are swapped in. If any swap fails, every target is restored.
+pub async fn batch_upload(
+    req: HttpRequest,
+    bytes: web::Payload,
+    shared: web::Data<SharedConfig>,
+) -> std::result::Result<HttpResponse, ApiError> {
+    let cfg = shared.current();
+    let identity = authenticate(&req, &cfg)?;
+    let _transfer = METRICS.transfer(Direction::Upload);
+    let mut budget = UploadBudget::new(&req, identity.max_upload_bytes(&cfg))?;
+    let forms = read_batch_form(&req, bytes, &mut budget).await?;
+    if forms.is_empty() {
+        return Err(ApiError::invalid("batch has no files"));
+    }
+    let targets: Vec<String> = forms
+        .iter()
+        .map(|form| form.target_file_path.clone())
+        .collect();
+
+    let (entries, hooks) = match write_batch(forms, &req, &identity, &cfg).await {
+        Ok(written) => written,
+        Err(err) => {
+            for target in targets.iter() {
+                // Every file fails with the batch, the error names the file that broke it.
+                let result: Result<(), ApiError> = Err(match err.path.as_deref() {
+                    Some(path) if path != target => ApiError {
+                        message: format!("batch failed: {}", err),
+                        ..err.clone()
+                    },
+                    _ => err.clone(),
+                });
+                AuditEntry::new(&req, &identity, AuditAction::Upload, target).record(&cfg, &result);
+            }
+            return Err(err);
+        }
+    };
+
+    let files: Vec<ApiResponse> = entries
+        .into_iter()
+        .map(|entry| {
+            AuditEntry::new(
+                &req,
+                &identity,
+                AuditAction::Upload,
+                &entry.form.target_file_path,
+            )
+            .with_write(Some(&entry.outcome))
+            .save(&cfg);
+            let message = if entry.outcome.changed {
+                "upload successfully"
+            } else {
+                "file is not changed"
+            };
+            entry
+                .outcome
+                .into_response(message, &entry.form.target_file_path)
+        })
+        .collect();
+    Ok(ApiResponse {
+        files,
+        hooks,
+        ..ApiResponse::ok("batch committed")
+    }
+    .into_http())
+}
+
+/// Validate, stage and swap in every file, or none of them. Validate hooks
+/// run on the staged files before any is swapped in, post hooks once every
+/// file is in place; a failed post hook rolls all back.
+async fn write_batch(
+    forms: Vec<UploadForm>,
+    req: &HttpRequest,
+    identity: &Identity,
+    cfg: &ServerConfig,
+) -> std::result::Result<(Vec<Staged>, Vec<HookOutcome>), ApiError> {
+    let mut seen = HashSet::new();
+    for form in forms.iter() {
+        validate_upload_args(form).map_err(|err| {
+            ApiError::invalid(format!("validate form err: {}", err))
+                .with_path(&form.target_file_path)
+        })?;
+        identity.check(cfg, &form.target_file_path, Access::Write)?;
+        if !seen.insert(form.target_file_path.clone()) {
+            return Err(
+                ApiError::conflict("duplicated target in batch").with_path(&form.target_file_path)
+            );
+        }
+    }
+
+    let hooked = hooks::hooked(cfg, forms.iter().map(|f| f.target_file_path.as_str()));
+    let _guard = if hooked {
+        Some(HOOK_LOCK.lock().await)
+    } else {
+        None
+    };
+
+    let batch_id = format!(
+        "{}-{}-{}",
+        Local::now().format("%Y%m%d%H%M%S"),
+        std::process::id(),
+        BATCH_SEQ.fetch_add(1, Ordering::Relaxed)
+    );
+    let mut entries = forms
+        .into_iter()
+        .map(|form| Staged::new(form, &batch_id))
+        .collect::<std::result::Result<Vec<Staged>, ApiError>>()?;
+    // Two links to one file would stage it twice.
+    let mut reals = HashSet::new();
+    if let Some(entry) = entries.iter().find(|e| !reals.insert(e.real.clone())) {
+        return Err(entry.error(ApiError::conflict("duplicated target in batch")));
+    }
+
+    if let Err(err) = stage(&mut entries, cfg).await {
+        discard(&entries).await;
+        return Err(err);
+    }
+    // The staged files hold the new content, so nothing goes live unvalidated.
+    let mut outcomes = Vec::new();
+    if hooked {
+        let staged: Vec<(String, String)> = entries
+            .iter()
+            .filter(|e| !e.unchanged)
+            .map(|e| {
+                (
+                    e.form.target_file_path.clone(),
+                    e.staged.to_string_lossy().to_string(),
+                )
+            })
+            .collect();
+        let staged: Vec<(&str, &str)> = staged
+            .iter()
+            .map(|(target, file)| (target.as_str(), file.as_str()))
+            .collect();
+        outcomes = hooks::validate(cfg, &staged, identity).await;
+        if hooks::rejected(&outcomes).is_some() {
+            discard(&entries).await;
+            let err = hooks::validation_error(outcomes);
+            return Err(ApiError {
+                message: format!("batch rejected: {}", err.message),
+                ..err
+            });
+        }
+    }
+    if let Err(err) = commit(&mut entries).await {
+        error!("batch({}) commit err, rolling back: {}", batch_id, err);
+        rollback(&entries, req, identity, cfg).await;
+        discard(&entries).await;
+        return Err(ApiError {
+            message: format!("batch rolled back: {}", err.message),
+            ..err
+        });
+    }
+    let changed: Vec<String> = entries
+        .iter()
+        .filter(|e| e.committed)
+        .map(|e| e.form.target_file_path.clone())
+        .collect();
+    let changed: Vec<&str> = changed.iter().map(String::as_str).collect();
+    if hooked && !changed.is_empty() {
+        // Before `finish`, so the originals can still be put back.
+        outcomes.extend(hooks::run(cfg, HookStage::Post, &changed, identity).await);
+        if hooks::post_failed(&outcomes) {
+            rollback(&entries, req, identity, cfg).await;
+            discard(&entries).await;
+            outcomes.extend(hooks::run(cfg, HookStage::Rollback, &changed, identity).await);
+            return Err(hooks::rollback_error(
+                outcomes,
+                "restored every file of the batch",
+            ));
+        }
+    }
+    finish(&mut entries, cfg).await;
+    Ok((entries, outcomes))
+}
+
+/// Parse `action` followed by repeated `target_file_path` + `file` pairs.
+/// An `action` or `mode` after a `target_file_path` only applies to that file.
+async fn read_batch_form(
+    req: &HttpRequest,
+    bytes: web::Payload,
+    budget: &mut UploadBudget,
+) -> std::result::Result<Vec<UploadForm>, ApiError> {
+    let mut multipart = Multipart::new(req.headers(), bytes);
+    let mut action = Action::default();
+    let mut forms: Vec<UploadForm> = Vec::new();
+
+    while let Some(chunk) = multipart.next().await {
+        let mut chunk =
+            chunk.map_err(|e| ApiError::invalid(format!("read multipart err: {}", e)))?;
+        let content_disposition = chunk.content_disposition().clone();
+        let key = content_disposition.get_name().unwrap_or("");
+        let value = read_content_disposition(&mut chunk, budget).await?;
+
+        match key {
+            "action" => {
+                let value = Action::from_str(&String::from_utf8_lossy(&value)).unwrap();
+                match forms.last_mut() {
+                    Some(form) => form.action = value,
+                    None => action = value,
+                }
+            }
+            "mode" => {
+                let mode = parse_mode(&String::from_utf8_lossy(&value))
+                    .ok_or_else(|| ApiError::invalid("mode is not an octal number"))?;
+                match forms.last_mut() {
+                    Some(form) => form.mode = Some(mode),
+                    None => return Err(ApiError::invalid("mode part before target_file_path")),
+                }
+            }
+            "target_file_path" => forms.push(UploadForm {
+                action,
+                target_file_path: String::from_utf8_lossy(&value).to_string(),
+                ..Default::default()
+            }),
+            "file" => match forms.last_mut() {
+                Some(form) => form.content = value,
+                None => return Err(ApiError::invalid("file part before target_file_path")),
+            },
+            _ => {
+                warn!("unknown action '{}'", key);
+            }
+        }
+    }
+
+    Ok(forms)
+}
+
+async fn stage(entries: &mut [Staged], cfg: &ServerConfig) -> std::result::Result<(), ApiError> {
+    // Find what changes and the room it takes before writing anything.
+    let mut needed = Vec::with_capacity(entries.len());
+    for entry in entries.iter_mut() {
+        let target = entry.target().to_path_buf();
+        if target.is_dir() {
+            return Err(entry.error(ApiError::conflict("target path is a directory")));
+        }
+        if target.exists() {
+            let old_content = tokio::fs::read(&target)
+                .await
+                .map_err(|err| entry.error(ApiError::io("read file err", err)))?;
+            let old_hash = content_md5(&old_content);
+            entry.outcome.old_hash = Some(old_hash.clone());
+            if old_hash == entry.outcome.hash {
+                debug!("file({:?}) is not changed.", target);
+                entry.unchanged = true;
+                entry.outcome.bytes = 0;
+                needed.push(0);
+                continue;
+            }
+        }
+        // The staged copy, plus the backup copy of the original in safe mode.
+        let mut bytes = entry.form.content.len() as u64;
+        if entry.form.action == Action::Safe {
+            bytes += tokio::fs::metadata(&target)
+                .await
+                .map(|m| m.len())
+                .unwrap_or(0);
+        }
+        needed.push(bytes);
+    }
+    ensure_batch_space(entries, &needed, cfg)?;
+
+    for entry in entries.iter_mut().filter(|e| !e.unchanged) {
+        let target = entry.target().to_path_buf();
+        if target.exists() {
+            if entry.form.action == Action::Safe {
+                let (dot_backup_dir, _) = backup_location(&target).map_err(|e| entry.error(e))?;
+                let missing = !Path::new(&dot_backup_dir).exists();
+                safe_create_backup_dir(&dot_backup_dir)
+                    .await
+                    .map_err(|e| entry.error(e))?;
+                if missing {
+                    entry.created_dirs.push(PathBuf::from(dot_backup_dir));
+                }
+            }
+        } else if let Some(dir) = target.parent() {
+            let missing: Vec<PathBuf> = dir
+                .ancestors()
+                .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+                .map(Path::to_path_buf)
+                .collect();
+            tokio::fs::create_dir_all(dir)
+                .await
+                .map_err(|err| entry.error(ApiError::io("create dir err", err)))?;
+            entry.created_dirs = missing;
+        }
+
+        tokio::fs::write(&entry.staged, &entry.form.content)
+            .await
+            .map_err(|err| entry.error(ApiError::io("stage file err", err)))?;
+        if entry.form.mode.is_none() && target.exists() {
+            keep_metadata(&target, &entry.staged)
+                .await
+                .map_err(|err| entry.error(err))?;
+        }
+        apply_mode(&entry.staged, entry.form.mode)
+            .await
+            .map_err(|err| entry.error(err))?;
+        debug!("staged {:?}", entry.staged);
+    }
+    Ok(())
+}
+
+/// A symlinked target resolved to the file it points at, so the link is kept
+/// and the file behind it is replaced, as `/upload` writes through it.
+fn real_target(target: &str) -> std::result::Result<PathBuf, ApiError> {
+    let path = Path::new(target);
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_symlink() => {
+            std::fs::canonicalize(path).map_err(|err| ApiError::io("resolve symlink err", err))
+        }
+        _ => Ok(path.to_path_buf()),
+    }
+}
+
+/// Give a staged file the mode of the file it replaces, and its owner where
+/// the server may set it, as the rename would otherwise drop both.
+async fn keep_metadata(original: &Path, staged: &Path) -> std::result::Result<(), ApiError> {
+    let meta = tokio::fs::metadata(original)
+        .await
+        .map_err(|err| ApiError::io("read metadata err", err))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if let Err(err) = std::os::unix::fs::chown(staged, Some(meta.uid()), Some(meta.gid())) {
+            debug!("keep owner of {:?} err: {}", original, err);
+        }
+    }
+    tokio::fs::set_permissions(staged, meta.permissions())
+        .await
+        .map_err(|err| ApiError::io("set mode err", err))
+}
+
+/// Files of a batch on one filesystem take its free space together, so each
+/// filesystem is checked once for their sum.
+fn ensure_batch_space(
+    entries: &[Staged],
+    needed: &[u64],
+    cfg: &ServerConfig,
+) -> std::result::Result<(), ApiError> {
+    if cfg.limits.min_free_bytes.is_none() {
+        return Ok(());
+    }
+    // (filesystem, first file on it, bytes needed on it)
+    let mut totals: Vec<(Option<u64>, &Staged, u64)> = Vec::new();
+    for (entry, bytes) in entries.iter().zip(needed).filter(|(_, bytes)| **bytes > 0) {
+        let fs = filesystem_id(entry.target());
+        match totals.iter_mut().find(|(id, ..)| *id == fs) {
+            Some(total) => total.2 += bytes,
+            None => totals.push((fs, entry, *bytes)),
+        }
+    }
+    for (_, entry, bytes) in totals {
+        ensure_free_space(cfg, entry.target(), bytes).map_err(|e| entry.error(e))?;
+    }
+    Ok(())
+}
+
+async fn commit(entries: &mut [Staged]) -> std::result::Result<(), ApiError> {
+    for entry in entries.iter_mut().filter(|e| !e.unchanged) {
+        if entry.target().exists() {
+            tokio::fs::rename(entry.target(), &entry.rollback)
+                .await
+                .map_err(|err| entry.error(ApiError::io("move original err", err)))?;
+            entry.has_original = true;
+        }
+        tokio::fs::rename(&entry.staged, entry.target())
+            .await
+            .map_err(|err| entry.error(ApiError::io("commit file err", err)))?;
+        entry.committed = true;
+    }
+    Ok(())
+}
+
+/// Put back the originals of committed files, and remove the ones that are new.
+async fn rollback(entries: &[Staged], req: &HttpRequest, identity: &Identity, cfg: &ServerConfig) {
+    for entry in entries
+        .iter()
+        .rev()
+        .filter(|e| e.has_original || e.committed)
+    {
+        let result = if entry.has_original {
+            tokio::fs::rename(&entry.rollback, entry.target()).await
+        } else {
+            tokio::fs::remove_file(entry.target()).await
+        };
+        if let Err(err) = &result {
+            error!("rollback {:?} err: {}", entry.target(), err);
+        }
+        let mut audit = AuditEntry::new(
+            req,
+            identity,
+            AuditAction::Restore,
+            &entry.form.target_file_path,
+        );
+        if entry.committed {
+            audit.old_hash = Some(entry.outcome.hash.clone());
+        }
+        audit.new_hash = entry.outcome.old_hash.clone();
+        audit.record(
+            cfg,
+            &result.map_err(|err| ApiError::io("rollback err", err)),
+        );
+    }
+}
+
+/// Remove the staged files, then the dirs staging created if they are empty again.
+async fn discard(entries: &[Staged]) {
+    for entry in entries.iter().filter(|e| e.staged.exists()) {
+        if let Err(err) = tokio::fs::remove_file(&entry.staged).await {
+            warn!("remove staged file {:?} err: {}", entry.staged, err);
+        }
+    }
+    for dir in entries.iter().rev().flat_map(|e| e.created_dirs.iter()) {
+        if let Err(err) = tokio::fs::remove_dir(dir).await {
+            warn!("remove dir {:?} created by batch err: {}", dir, err);
+        }
+    }
+}
+
+/// Turn the moved-aside originals into backups (safe) or drop them (force).
+async fn finish(entries: &mut [Staged], cfg: &ServerConfig) {
+    for entry in entries.iter_mut() {
+        if entry.unchanged {
+            if let Err(err) = apply_mode(entry.target(), entry.form.mode).await {
+                warn!("set mode of {:?} err: {}", entry.target(), err);
+            }
+            continue;
+        }
+        entry.outcome.changed = true;
+        METRICS.uploaded(entry.outcome.bytes);
+        if !entry.has_original {
+            continue;
+        }
+        match entry.form.action {
+            Action::Safe => {
+                let backup_file = match backup_location(entry.target()) {
+                    Ok((_, backup_file)) => backup_file,
+                    Err(err) => {
+                        error!("backup {:?} err: {}", entry.target(), err);
+                        continue;
+                    }
+                };
+                let bytes = tokio::fs::metadata(&entry.rollback)
+                    .await
+                    .map(|m| m.len())
+                    .unwrap_or(0);
+                match tokio::fs::rename(&entry.rollback, &backup_file).await {
+                    Ok(_) => {
+                        debug!("backup file({}) ok", backup_file);
+                        METRICS.backup_created(bytes);
+                        prune_backups(&backup_file, cfg.backup.keep).await;
+                        entry.outcome.backup = Some(backup_file);
+                    }
+                    Err(err) => error!(
+                        "backup {:?} err: {}, original kept at {:?}",
+                        entry.target(),
+                        err,
+                        entry.rollback
+                    ),
+                }
+            }
+            Action::Force => {
+                if let Err(err) = tokio::fs::remove_file(&entry.rollback).await {
+                    warn!("remove rollback file {:?} err: {}", entry.rollback, err);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use actix_web::HttpResponse;
+use serde::{Deserialize, Serialize};
+
+use crate::apis::response::ApiResponse;
+use crate::util::compress;
+
+/// Protocol version of the routes under `/v1`.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Names of optional features a server may support.
+pub mod feature {
+    /// `/stat` and every write answer with md5 hashes.
+    pub const HASHES: &str = "hashes";
+    /// Uploaded parts may be compressed with one of `Capabilities::encodings`.
+    pub const COMPRESSION: &str = "compression";
+    /// Upload sessions under `/sessions`.
+    pub const RESUMABLE: &str = "resumable";
+    /// All-or-nothing uploads with `/batch`.
+    pub const BATCH: &str = "batch";
+    pub const BACKUPS: &str = "backups";
+    pub const DELETE: &str = "delete";
+    pub const LIST: &str = "list";
+}
+
+/// What a server speaks, from `GET /capabilities`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Capabilities {
+    /// Protocol versions served, each under `/v<N>`. Empty for a server
+    /// older than versioning, which serves the routes without a prefix.
+    pub versions: Vec<u32>,
+    pub server_version: String,
+    pub features: Vec<String>,
+    /// Content codings accepted for uploaded parts.
+    pub encodings: Vec<String>,
+}
+
+impl Capabilities {
+    /// The capabilities of this build.
+    pub fn current() -> Self {
+        Capabilities {
+            versions: vec![PROTOCOL_VERSION],
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+            features: [
+                feature::HASHES,
+                feature::COMPRESSION,
+                feature::RESUMABLE,
+                feature::BATCH,
+                feature::BACKUPS,
+                feature::DELETE,
+                feature::LIST,
+            ]
+            .iter()
+            .map(|feature| feature.to_string())
+            .collect(),
+            encodings: compress::SUPPORTED
+                .split(',')
+                .map(|encoding| encoding.trim().to_string())
+                .collect(),
+        }
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+/// Served outside of any version scope, so every client can ask it first.
+pub async fn capabilities() -> HttpResponse {
+    ApiResponse {
+        capabilities: Some(Capabilities::current()),
+        ..ApiResponse::ok("capabilities")
+    }
+    .into_http()
+}
@@ -0,0 +1,95 @@
+use std::path;
+use std::str::FromStr;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::debug;
+use serde::Deserialize;
+
+use crate::apis::audit::{AuditAction, AuditEntry};
+use crate::apis::auth::{authenticate, Identity};
+use crate::apis::metrics::METRICS;
+use crate::apis::response::{ApiError, ApiResponse};
+use crate::apis::upload::{backup_location, file_md5, prune_backups, safe_create_backup_dir};
+use crate::config::{Access, ServerConfig, SharedConfig};
+use crate::util::schema::Action;
+
+#[derive(Deserialize)]
+pub struct DeleteReq {
+    file_path: String,
+    #[serde(default)]
+    action: Option<String>,
+}
+
+/// Remove a file. In safe mode it is moved into its backup dir instead, so it
+/// can be restored like any overwritten version.
+pub async fn delete_file(
+    http_req: HttpRequest,
+    req: web::Json<DeleteReq>,
+    shared: web::Data<SharedConfig>,
+) -> Result<HttpResponse, ApiError> {
+    if req.file_path.is_empty() {
+        return Err(ApiError::invalid("invalid file path"));
+    }
+    let cfg = shared.current();
+    let identity = authenticate(&http_req, &cfg)?;
+    let action = Action::from_str(req.action.as_deref().unwrap_or_default()).unwrap_or_default();
+    debug!(
+        "delete by {}: {}, action={}",
+        identity.name(),
+        req.file_path,
+        action
+    );
+
+    let mut entry = AuditEntry::new(&http_req, &identity, AuditAction::Delete, &req.file_path);
+    let result = remove(&req.file_path, action, &identity, &cfg, &mut entry).await;
+    entry.record(&cfg, &result);
+    let mut response = ApiResponse::ok("delete successfully").with_path(&req.file_path);
+    response.backup = result?;
+    Ok(response.into_http())
+}
+
+/// Remove or back up the file, and return the backup path.
+async fn remove(
+    path: &str,
+    action: Action,
+    identity: &Identity,
+    cfg: &ServerConfig,
+    entry: &mut AuditEntry,
+) -> Result<Option<String>, ApiError> {
+    identity.check(cfg, path, Access::Write)?;
+    let file_path = path::Path::new(path);
+    if !file_path.exists() {
+        return Err(ApiError::not_found("not found path").with_path(path));
+    }
+    if file_path.is_dir() {
+        return Err(ApiError::conflict("path is a directory").with_path(path));
+    }
+    entry.old_hash = file_md5(file_path).await;
+
+    match action {
+        Action::Safe => {
+            let (dot_backup_dir, backup_file) =
+                backup_location(file_path).map_err(|err| err.with_path(path))?;
+            safe_create_backup_dir(&dot_backup_dir)
+                .await
+                .map_err(|err| err.with_path(path))?;
+            let bytes = tokio::fs::metadata(file_path)
+                .await
+                .map(|m| m.len())
+                .unwrap_or(0);
+            tokio::fs::rename(file_path, &backup_file)
+                .await
+                .map_err(|err| ApiError::io("move to backup err", err).with_path(path))?;
+            METRICS.backup_created(bytes);
+            prune_backups(&backup_file, cfg.backup.keep).await;
+            entry.backup = Some(backup_file.clone());
+            Ok(Some(backup_file))
+        }
+        Action::Force => {
+            tokio::fs::remove_file(file_path)
+                .await
+                .map_err(|err| ApiError::io("delete file err", err).with_path(path))?;
+            Ok(None)
+        }
+    }
+}
@@ -1,30 +1,117 @@
 use std::path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
-use actix_web::{web, Error, HttpResponse, Responder, Result};
-use futures::{future::ok, stream::once};
+use actix_web::{http::header, web, Error, HttpRequest, HttpResponse};
+use futures::Stream;
+use log::debug;
 use serde::Deserialize;
 use tokio::fs;
 
+use crate::apis::audit::{AuditAction, AuditEntry};
+use crate::apis::auth::{authenticate, Identity};
+use crate::apis::metrics::{Direction, TransferGuard, METRICS};
+use crate::apis::response::{ApiError, ErrorCode};
+use crate::apis::upload::content_md5;
+use crate::config::{Access, ServerConfig, SharedConfig};
+use crate::util::compress::is_compressed_format;
+
+/// Response header carrying the md5 of a downloaded file.
+pub const HASH_HEADER: &str = "x-sync-file-md5";
+
 #[derive(Deserialize)]
 pub struct DownloadReq {
     file_path: String,
 }
 
-pub async fn download_file(req: web::Json<DownloadReq>) -> Result<impl Responder> {
+pub async fn download_file(
+    http_req: HttpRequest,
+    req: web::Json<DownloadReq>,
+    shared: web::Data<SharedConfig>,
+) -> Result<HttpResponse, ApiError> {
     if req.file_path.is_empty() {
-        return Ok(HttpResponse::BadRequest().body("invalid file path".to_string()));
+        return Err(ApiError::invalid("invalid file path"));
     }
+    let cfg = shared.current();
+    let identity = authenticate(&http_req, &cfg)?;
+    debug!("download by {}: {}", identity.name(), req.file_path);
 
+    let result = read_file(&req.file_path, &identity, &cfg).await;
+    let mut entry = AuditEntry::new(&http_req, &identity, AuditAction::Download, &req.file_path);
+    let hash = result.as_ref().ok().map(|content| content_md5(content));
+    if let Ok(content) = &result {
+        // The file is not changed by a download.
+        entry.old_hash = hash.clone();
+        entry.new_hash = hash.clone();
+        entry.bytes = Some(content.len() as u64);
+    }
+    entry.record(&cfg, &result);
+    let content = result?;
+    METRICS.downloaded(content.len() as u64);
     let file_path = path::Path::new(&req.file_path);
+    let body = TransferBody {
+        content: Some(web::Bytes::from(content)),
+        _transfer: METRICS.transfer(Direction::Download),
+    };
+
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type("application/octet-stream")
+        .insert_header((HASH_HEADER, hash.unwrap_or_default()));
+    // Tell the Compress middleware to leave formats alone that do not shrink.
+    if is_compressed_format(file_path) {
+        response.insert_header((header::CONTENT_ENCODING, "identity"));
+    }
+    Ok(response.streaming(body))
+}
+
+async fn read_file(
+    path: &str,
+    identity: &Identity,
+    cfg: &ServerConfig,
+) -> Result<Vec<u8>, ApiError> {
+    identity.check(cfg, path, Access::Read)?;
+    let file_path = path::Path::new(path);
     if !file_path.exists() {
-        return Ok(HttpResponse::BadRequest().body(format!("not found path: {}", req.file_path)));
+        return Err(ApiError::not_found("not found path").with_path(path));
+    }
+    if file_path.is_dir() {
+        return Err(ApiError::conflict("path is a directory").with_path(path));
     }
 
-    let body = once(ok::<_, Error>(web::Bytes::from(
-        fs::read(&req.file_path).await?,
-    )));
+    if let Some(limit) = identity.max_download_bytes(cfg) {
+        let bytes = fs::metadata(file_path)
+            .await
+            .map_err(|err| ApiError::io("stat file err", err).with_path(path))?
+            .len();
+        if bytes > limit {
+            return Err(ApiError::new(
+                ErrorCode::PayloadTooLarge,
+                format!(
+                    "file of {} bytes exceeds the download limit of {} bytes",
+                    bytes, limit
+                ),
+            )
+            .with_path(path));
+        }
+    }
+
+    fs::read(path)
+        .await
+        .map_err(|err| ApiError::io("read file err", err).with_path(path))
+}
 
-    Ok(HttpResponse::Ok()
-        .content_type("application/json")
-        .streaming(body))
+/// A downloaded file as the response body, counted as an active transfer
+/// until the body is dropped: once it is sent or the client is gone.
+struct TransferBody {
+    content: Option<web::Bytes>,
+    _transfer: TransferGuard,
+}
+
+impl Stream for TransferBody {
+    type Item = Result<web::Bytes, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(self.get_mut().content.take().map(Ok))
+    }
 }
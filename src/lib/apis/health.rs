@@ -0,0 +1,153 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::LazyLock;
+use std::time::Instant;
+
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::apis::metrics::METRICS;
+use crate::apis::response::{ApiError, ApiResponse, ErrorCode};
+use crate::config::{ServerConfig, SharedConfig};
+use crate::util::file::available_space;
+
+/// When the server started, forced by `main` so uptime does not start at the first check.
+pub static STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+static PROBE_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// What `/healthz` and `/readyz` report.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Health {
+    pub version: String,
+    pub uptime_secs: u64,
+    /// Every root is writable with enough free space, and backups can be made.
+    pub ready: bool,
+    pub roots: Vec<RootHealth>,
+    pub backups: BackupHealth,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RootHealth {
+    pub path: String,
+    pub writable: bool,
+    pub free_bytes: Option<u64>,
+    /// Free space is below `limits.min_free_bytes`, so writes get 507.
+    pub low_space: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Safe mode backups live in `.[file_stem]` dirs next to their files.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BackupHealth {
+    /// A backup dir can be created in every root.
+    pub ok: bool,
+    /// Bytes of backups below the roots.
+    pub bytes: u64,
+    pub keep: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Liveness: answers 200 as long as the server runs, with what is wrong if anything.
+pub async fn healthz(shared: web::Data<SharedConfig>) -> Result<HttpResponse, ApiError> {
+    let health = check(shared.current()).await?;
+    Ok(ApiResponse {
+        health: Some(health),
+        ..ApiResponse::ok("healthy")
+    }
+    .into_http())
+}
+
+/// Readiness: answers 503 unless the server can take writes.
+pub async fn readyz(shared: web::Data<SharedConfig>) -> Result<HttpResponse, ApiError> {
+    let health = check(shared.current()).await?;
+    let response = if health.ready {
+        ApiResponse::ok("ready")
+    } else {
+        ApiResponse {
+            ok: false,
+            code: Some(ErrorCode::Unavailable),
+            message: "not ready".to_string(),
+            ..Default::default()
+        }
+    };
+    Ok(ApiResponse {
+        health: Some(health),
+        ..response
+    }
+    .into_http())
+}
+
+async fn check(cfg: std::sync::Arc<ServerConfig>) -> Result<Health, ApiError> {
+    web::block(move || {
+        let roots: Vec<RootHealth> = cfg
+            .roots
+            .iter()
+            .map(|root| check_root(root, cfg.limits.min_free_bytes))
+            .collect();
+        let backup_error = cfg
+            .roots
+            .iter()
+            .find_map(|root| probe_backup_dir(root).err());
+        let backups = BackupHealth {
+            ok: backup_error.is_none(),
+            bytes: METRICS.backup_bytes(),
+            keep: cfg.backup.keep,
+            error: backup_error,
+        };
+        Health {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            uptime_secs: STARTED.elapsed().as_secs(),
+            ready: backups.ok && roots.iter().all(|root| root.writable && !root.low_space),
+            roots,
+            backups,
+        }
+    })
+    .await
+    .map_err(|err| ApiError::internal(err.to_string()))
+}
+
+fn check_root(root: &str, min_free: Option<u64>) -> RootHealth {
+    let mut health = RootHealth {
+        path: root.to_string(),
+        ..Default::default()
+    };
+    match available_space(root) {
+        Ok(free) => {
+            health.free_bytes = Some(free);
+            health.low_space = min_free.is_some_and(|min_free| free < min_free);
+        }
+        Err(err) => health.error = Some(format!("check free space err: {}", err)),
+    }
+    match probe_file(root) {
+        Ok(()) => health.writable = true,
+        Err(err) => health.error = Some(err),
+    }
+    health
+}
+
+fn probe_name(root: &str) -> std::path::PathBuf {
+    Path::new(root).join(format!(
+        ".sync-health-{}-{}",
+        std::process::id(),
+        PROBE_SEQ.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Write and remove a file in `root`.
+fn probe_file(root: &str) -> Result<(), String> {
+    let probe = probe_name(root);
+    std::fs::write(&probe, b"")
+        .and_then(|_| std::fs::remove_file(&probe))
+        .map_err(|err| format!("write {:?} err: {}", probe, err))
+}
+
+/// Create and remove a hidden dir in `root`, like a backup dir.
+fn probe_backup_dir(root: &str) -> Result<(), String> {
+    let probe = probe_name(root);
+    std::fs::create_dir(&probe)
+        .and_then(|_| std::fs::remove_dir(&probe))
+        .map_err(|err| format!("create backup dir {:?} err: {}", probe, err))
+}
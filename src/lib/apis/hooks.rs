@@ -0,0 +1,456 @@
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::apis::auth::Identity;
+use crate::apis::response::{ApiError, ErrorCode};
+use crate::config::{HookConfig, ServerConfig};
+
+/// Output kept per command, the end of it when longer.
+const MAX_OUTPUT: usize = 16 * 1024;
+
+/// How long output of a finished or killed command is still read from its pipes.
+const PIPE_GRACE: Duration = Duration::from_secs(1);
+
+/// Writes to hooked files run one at a time, so a validation never sees
+/// another hooked write half done.
+pub(crate) static HOOK_LOCK: Mutex<()> = Mutex::const_new(());
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HookStage {
+    Validate,
+    Post,
+    /// The post command again, after a failed one rolled the write back.
+    Rollback,
+}
+
+impl HookStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Validate => "validate",
+            Self::Post => "post",
+            Self::Rollback => "rollback",
+        }
+    }
+
+    fn command<'a>(&self, hook: &'a HookConfig) -> Option<&'a str> {
+        match self {
+            Self::Validate => hook.validate.as_deref(),
+            Self::Post | Self::Rollback => hook.post.as_deref(),
+        }
+    }
+}
+
+/// One command run by a write.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HookOutcome {
+    pub stage: HookStage,
+    pub command: String,
+    /// Targets written that matched the hook.
+    pub paths: Vec<String>,
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub timed_out: bool,
+    /// stdout then stderr, cut to the last 16 KiB.
+    pub output: String,
+    pub duration_ms: u64,
+}
+
+/// Whether any hook matches one of `paths`.
+pub(crate) fn hooked<'a>(cfg: &ServerConfig, mut paths: impl Iterator<Item = &'a str>) -> bool {
+    !cfg.hooks.is_empty() && paths.any(|path| cfg.hooks.iter().any(|hook| hook.matches(path)))
+}
+
+/// Run the `stage` command of every hook matching one of `paths`, in config
+/// order. A post command runs once for all of its files, so a batch reloads once.
+pub(crate) async fn run(
+    cfg: &ServerConfig,
+    stage: HookStage,
+    paths: &[&str],
+    identity: &Identity,
+) -> Vec<HookOutcome> {
+    let files: Vec<(&str, &str)> = paths.iter().map(|path| (*path, *path)).collect();
+    run_on(cfg, stage, &files, identity).await
+}
+
+/// Run the validate command of every hook matching a target before it is
+/// written, on the file holding its new content: `files` pairs each target
+/// with that file. Validations run once per file and stop at the first failure.
+pub(crate) async fn validate(
+    cfg: &ServerConfig,
+    files: &[(&str, &str)],
+    identity: &Identity,
+) -> Vec<HookOutcome> {
+    run_on(cfg, HookStage::Validate, files, identity).await
+}
+
+async fn run_on(
+    cfg: &ServerConfig,
+    stage: HookStage,
+    files: &[(&str, &str)],
+    identity: &Identity,
+) -> Vec<HookOutcome> {
+    let mut outcomes = Vec::new();
+    for hook in cfg.hooks.iter() {
+        let Some(command) = stage.command(hook) else {
+            continue;
+        };
+        let matched: Vec<(String, String)> = files
+            .iter()
+            .filter(|(target, _)| hook.matches(target))
+            .map(|(target, file)| (target.to_string(), file.to_string()))
+            .collect();
+        if matched.is_empty() {
+            continue;
+        }
+        let runs = match stage {
+            HookStage::Validate => matched.into_iter().map(|path| vec![path]).collect(),
+            HookStage::Post | HookStage::Rollback => vec![matched],
+        };
+        for paths in runs {
+            let outcome = run_command(stage, command, paths, hook.timeout_secs, identity).await;
+            let failed = !outcome.ok;
+            outcomes.push(outcome);
+            if failed && stage == HookStage::Validate {
+                return outcomes;
+            }
+        }
+    }
+    outcomes
+}
+
+/// The validation that rejected a write, if any.
+pub(crate) fn rejected(outcomes: &[HookOutcome]) -> Option<&HookOutcome> {
+    outcomes
+        .iter()
+        .find(|outcome| outcome.stage == HookStage::Validate && !outcome.ok)
+}
+
+/// 422 naming the rejected file, carrying the outcomes of the validations that ran.
+pub(crate) fn validation_error(outcomes: Vec<HookOutcome>) -> ApiError {
+    let (message, path) = match rejected(&outcomes) {
+        Some(failed) => (
+            format!(
+                "validate hook '{}' {}, previous version kept",
+                failed.command,
+                failed.describe()
+            ),
+            failed.paths.first().cloned(),
+        ),
+        None => (
+            "validate hook failed, previous version kept".to_string(),
+            None,
+        ),
+    };
+    ApiError {
+        path,
+        hooks: outcomes,
+        ..ApiError::new(ErrorCode::ValidationFailed, message)
+    }
+}
+
+/// Whether a post command failed, so the write has to be rolled back.
+pub(crate) fn post_failed(outcomes: &[HookOutcome]) -> bool {
+    outcomes
+        .iter()
+        .any(|outcome| outcome.stage == HookStage::Post && !outcome.ok)
+}
+
+/// 422 telling a failed post command rolled the write back, `restored`
+/// saying how, carrying every hook that ran, the re-run included.
+pub(crate) fn rollback_error(outcomes: Vec<HookOutcome>, restored: &str) -> ApiError {
+    let mut message = match outcomes
+        .iter()
+        .find(|outcome| outcome.stage == HookStage::Post && !outcome.ok)
+    {
+        Some(failed) => format!(
+            "post hook '{}' {}, rolled back: {}",
+            failed.command,
+            failed.describe(),
+            restored
+        ),
+        None => format!("post hook failed, rolled back: {}", restored),
+    };
+    if outcomes
+        .iter()
+        .any(|outcome| outcome.stage == HookStage::Rollback && !outcome.ok)
+    {
+        message.push_str(", but the hook failed again after it");
+    }
+    ApiError {
+        hooks: outcomes,
+        ..ApiError::new(ErrorCode::RolledBack, message)
+    }
+}
+
+impl HookOutcome {
+    /// How the command ended, e.g. `exited with 1`.
+    pub fn describe(&self) -> String {
+        if self.timed_out {
+            "timed out".to_string()
+        } else {
+            match self.exit_code {
+                Some(code) => format!("exited with {}", code),
+                None if self.ok => "succeeded".to_string(),
+                None => "was killed".to_string(),
+            }
+        }
+    }
+}
+
+/// Run `command` on `files`, pairs of a target and the file to hand over.
+async fn run_command(
+    stage: HookStage,
+    command: &str,
+    files: Vec<(String, String)>,
+    timeout_secs: u64,
+    identity: &Identity,
+) -> HookOutcome {
+    let (paths, files): (Vec<String>, Vec<String>) = files.into_iter().unzip();
+    let mut outcome = HookOutcome {
+        stage,
+        command: command.to_string(),
+        paths,
+        ok: false,
+        exit_code: None,
+        timed_out: false,
+        output: String::new(),
+        duration_ms: 0,
+    };
+    let mut std_cmd = std::process::Command::new("sh");
+    std_cmd
+        .arg("-c")
+        .arg(command)
+        .env("SYNC_HOOK_STAGE", stage.as_str())
+        .env("SYNC_FILE", &files[0])
+        .env("SYNC_FILES", files.join("\n"))
+        .env("SYNC_TARGET", &outcome.paths[0])
+        .env("SYNC_IDENTITY", identity.name())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // Its own group, so a timeout also kills what the shell started.
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut std_cmd, 0);
+    let mut cmd = Command::from(std_cmd);
+    cmd.kill_on_drop(true);
+
+    let started = Instant::now();
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            outcome.output = format!("spawn sh err: {}", err);
+            warn!("{} hook '{}' {}", stage.as_str(), command, outcome.output);
+            return outcome;
+        }
+    };
+    let pid = child.id();
+    // Read apart from the wait, so a killed command keeps what it printed.
+    let (stdout, stdout_task) = drain(child.stdout.take());
+    let (stderr, stderr_task) = drain(child.stderr.take());
+    let result = tokio::time::timeout(Duration::from_secs(timeout_secs), child.wait()).await;
+    outcome.duration_ms = started.elapsed().as_millis() as u64;
+    match result {
+        Ok(Ok(status)) => {
+            outcome.ok = status.success();
+            outcome.exit_code = status.code();
+        }
+        Ok(Err(err)) => outcome.output = format!("wait for command err: {}\n", err),
+        Err(_) => {
+            outcome.timed_out = true;
+            #[cfg(unix)]
+            if let Some(pid) = pid {
+                // SAFETY: signals the process group started above, nothing is dereferenced.
+                unsafe {
+                    libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+                }
+            }
+            #[cfg(not(unix))]
+            let _ = pid;
+        }
+    }
+    // A process that left the group may hold the pipes open forever.
+    let _ = tokio::time::timeout(PIPE_GRACE, async {
+        let _ = stdout_task.await;
+        let _ = stderr_task.await;
+    })
+    .await;
+    let mut text = std::mem::take(&mut *stdout.lock().unwrap());
+    text.extend(stderr.lock().unwrap().iter());
+    outcome.output.push_str(&truncate(&text));
+    if outcome.ok {
+        debug!(
+            "{} hook '{}' ok in {}ms",
+            stage.as_str(),
+            command,
+            outcome.duration_ms
+        );
+    } else {
+        warn!(
+            "{} hook '{}' {} for {:?}",
+            stage.as_str(),
+            command,
+            outcome.describe(),
+            outcome.paths
+        );
+    }
+    outcome
+}
+
+/// Read `pipe` to its end in a task, keeping the last `MAX_OUTPUT` bytes in
+/// the buffer returned as they arrive.
+fn drain<R>(pipe: Option<R>) -> (Arc<std::sync::Mutex<Vec<u8>>>, JoinHandle<()>)
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let output = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let buf = output.clone();
+    let task = tokio::spawn(async move {
+        let Some(mut pipe) = pipe else {
+            return;
+        };
+        let mut chunk = [0; 8192];
+        while let Ok(n) = pipe.read(&mut chunk).await {
+            if n == 0 {
+                break;
+            }
+            let mut buf = buf.lock().unwrap();
+            buf.extend_from_slice(&chunk[..n]);
+            if buf.len() > 2 * MAX_OUTPUT {
+                // One byte more than kept, so `truncate` still marks the cut.
+                let excess = buf.len() - MAX_OUTPUT - 1;
+                buf.drain(..excess);
+            }
+        }
+    });
+    (output, task)
+}
+
+fn truncate(output: &[u8]) -> String {
+    if output.len() <= MAX_OUTPUT {
+        return String::from_utf8_lossy(output).to_string();
+    }
+    format!(
+        "...{}",
+        String::from_utf8_lossy(&output[output.len() - MAX_OUTPUT..])
+    )
+}
+
+/// A file as it was before a hooked write, to put back if a post hook fails.
+pub(crate) struct Snapshot {
+    content: Option<Vec<u8>>,
+    permissions: Option<std::fs::Permissions>,
+}
+
+impl Snapshot {
+    pub(crate) async fn take(path: &Path) -> Result<Self, ApiError> {
+        match tokio::fs::read(path).await {
+            Ok(content) => Ok(Snapshot {
+                content: Some(content),
+                permissions: tokio::fs::metadata(path)
+                    .await
+                    .ok()
+                    .map(|m| m.permissions()),
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Snapshot {
+                content: None,
+                permissions: None,
+            }),
+            Err(err) => Err(ApiError::io("read file err", err)),
+        }
+    }
+
+    /// Whether writing `content` changes the file.
+    pub(crate) fn differs(&self, content: &[u8]) -> bool {
+        self.content.as_deref() != Some(content)
+    }
+
+    /// Write the old content and mode back, or remove a file that did not exist.
+    pub(crate) async fn restore(&self, path: &Path) -> Result<(), ApiError> {
+        let Some(content) = &self.content else {
+            return tokio::fs::remove_file(path)
+                .await
+                .map_err(|err| ApiError::io("remove new file err", err));
+        };
+        tokio::fs::write(path, content)
+            .await
+            .map_err(|err| ApiError::io("restore previous version err", err))?;
+        if let Some(permissions) = &self.permissions {
+            tokio::fs::set_permissions(path, permissions.clone())
+                .await
+                .map_err(|err| ApiError::io("restore mode err", err))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncate_keeps_the_tail() {
+        let output = vec![b'a'; MAX_OUTPUT];
+        assert_eq!(truncate(&output).len(), MAX_OUTPUT);
+
+        let mut output = vec![b'a'; MAX_OUTPUT];
+        output.insert(0, b'b');
+        let kept = truncate(&output);
+        assert!(kept.starts_with("...a"));
+        assert_eq!(kept.len(), MAX_OUTPUT + 3);
+    }
+
+    #[test]
+    fn truncate_replaces_invalid_utf8() {
+        assert_eq!(truncate(b"ok \xff"), "ok \u{fffd}");
+    }
+
+    #[tokio::test]
+    async fn drain_reads_all_short_output() {
+        let (output, task) = drain(Some(&b"hello\n"[..]));
+        task.await.unwrap();
+        assert_eq!(*output.lock().unwrap(), b"hello\n");
+
+        let (output, task) = drain(None::<&[u8]>);
+        task.await.unwrap();
+        assert!(output.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn drain_keeps_enough_to_mark_the_cut() {
+        let mut input = vec![b'x'; 10 * MAX_OUTPUT];
+        input.extend_from_slice(b"end");
+        let (output, task) = drain(Some(std::io::Cursor::new(input)));
+        task.await.unwrap();
+        let output = output.lock().unwrap();
+        assert!(output.len() > MAX_OUTPUT && output.len() <= 2 * MAX_OUTPUT);
+        assert!(output.ends_with(b"end"));
+        let kept = truncate(&output);
+        assert!(kept.starts_with("...") && kept.ends_with("end"));
+        assert_eq!(kept.len(), MAX_OUTPUT + 3);
+    }
+
+    #[tokio::test]
+    async fn drain_keeps_output_read_before_a_stall() {
+        let (mut writer, reader) = tokio::io::duplex(64);
+        let (output, task) = drain(Some(reader));
+        tokio::io::AsyncWriteExt::write_all(&mut writer, b"partial")
+            .await
+            .unwrap();
+        // The pipe stays open, like a killed command's orphaned child holding it.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(*output.lock().unwrap(), b"partial");
+        task.abort();
+    }
+}
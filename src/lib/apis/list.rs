@@ -0,0 +1,108 @@
+use std::path::Path;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::debug;
+use serde::Deserialize;
+
+use crate::apis::auth::authorize;
+use crate::apis::response::{ApiError, ApiResponse};
+use crate::apis::upload::content_md5;
+use crate::config::{Access, SharedConfig};
+
+#[derive(Deserialize)]
+pub struct ListReq {
+    dir_path: String,
+}
+
+/// Every file below `dir_path`, with paths relative to it.
+pub async fn list_dir(
+    http_req: HttpRequest,
+    req: web::Json<ListReq>,
+    shared: web::Data<SharedConfig>,
+) -> Result<HttpResponse, ApiError> {
+    if req.dir_path.is_empty() {
+        return Err(ApiError::invalid("invalid dir path"));
+    }
+    let cfg = shared.current();
+    let identity = authorize(&http_req, &cfg, &req.dir_path, Access::Read)?;
+    debug!("list by {}: {}", identity.name(), req.dir_path);
+
+    let dir = Path::new(&req.dir_path);
+    if !dir.exists() {
+        return Err(ApiError::not_found("not found path").with_path(&req.dir_path));
+    }
+    if !dir.is_dir() {
+        return Err(ApiError::conflict("path is not a directory").with_path(&req.dir_path));
+    }
+
+    let dir_path = req.dir_path.clone();
+    let files = web::block(move || list_files(Path::new(&dir_path)))
+        .await
+        .map_err(|err| ApiError::internal(err.to_string()))?
+        .map_err(|err| ApiError::io("list dir err", err).with_path(&req.dir_path))?;
+
+    Ok(ApiResponse {
+        files,
+        ..ApiResponse::ok("list successfully").with_path(&req.dir_path)
+    }
+    .into_http())
+}
+
+/// Walk `dir` recursively, sorted by path. Backup dirs of safe mode and
+/// leftovers of batch uploads are not part of the listing.
+pub fn list_files(dir: &Path) -> std::io::Result<Vec<ApiResponse>> {
+    let mut files = Vec::new();
+    walk(dir, "", &mut files)?;
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+fn walk(dir: &Path, prefix: &str, files: &mut Vec<ApiResponse>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let relative = if prefix.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if !is_backup_dir(&entry.path()) {
+                walk(&entry.path(), &relative, files)?;
+            }
+        } else if file_type.is_file()
+            && !name.contains(".sync-batch-")
+            && !name.contains(".sync-rollback-")
+        {
+            let content = std::fs::read(entry.path())?;
+            files.push(ApiResponse {
+                ok: true,
+                path: Some(relative),
+                bytes: Some(content.len() as u64),
+                hash: Some(content_md5(&content)),
+                ..Default::default()
+            });
+        }
+    }
+    Ok(())
+}
+
+/// A `.[file_stem]` dir holding nothing but `[filename].[%Y%m%d_%H%M%S]` backups.
+/// It stays one after its file was deleted in safe mode.
+pub(crate) fn is_backup_dir(dir: &Path) -> bool {
+    let is_hidden = dir
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return false;
+    };
+    is_hidden
+        && entries.flatten().all(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            entry.file_type().is_ok_and(|t| t.is_file())
+                && name.rsplit_once('.').is_some_and(|(_, stamp)| {
+                    chrono::NaiveDateTime::parse_from_str(stamp, "%Y%m%d_%H%M%S").is_ok()
+                })
+        })
+}
@@ -0,0 +1,287 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::http::{Method, StatusCode};
+use actix_web::HttpResponse;
+use log::debug;
+
+use crate::apis::list::is_backup_dir;
+
+/// Upper bounds in seconds of the request duration buckets.
+const BUCKETS: [f64; 13] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+/// Counters of this server process, scraped from `GET /metrics`.
+pub static METRICS: Metrics = Metrics::new();
+
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket of `BUCKETS`, plus one for `+Inf`.
+    counts: [u64; BUCKETS.len() + 1],
+    sum: f64,
+}
+
+pub struct Metrics {
+    /// Responses by route, method and status.
+    requests: Mutex<BTreeMap<(String, String, u16), u64>>,
+    /// Request durations by route.
+    durations: Mutex<BTreeMap<String, Histogram>>,
+    uploaded_bytes: AtomicU64,
+    downloaded_bytes: AtomicU64,
+    backups_created: AtomicU64,
+    backups_pruned: AtomicU64,
+    backup_bytes: AtomicI64,
+    active_uploads: AtomicI64,
+    active_downloads: AtomicI64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            requests: Mutex::new(BTreeMap::new()),
+            durations: Mutex::new(BTreeMap::new()),
+            uploaded_bytes: AtomicU64::new(0),
+            downloaded_bytes: AtomicU64::new(0),
+            backups_created: AtomicU64::new(0),
+            backups_pruned: AtomicU64::new(0),
+            backup_bytes: AtomicI64::new(0),
+            active_uploads: AtomicI64::new(0),
+            active_downloads: AtomicI64::new(0),
+        }
+    }
+
+    /// Count a response of `route`, the pattern it matched, e.g. `/sessions/{id}`.
+    pub fn observe_request(
+        &self,
+        route: &str,
+        method: &Method,
+        status: StatusCode,
+        elapsed: Duration,
+    ) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((route.to_string(), method.to_string(), status.as_u16()))
+            .or_default() += 1;
+
+        let seconds = elapsed.as_secs_f64();
+        let mut durations = self.durations.lock().unwrap();
+        let histogram = durations.entry(route.to_string()).or_default();
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(BUCKETS.len());
+        histogram.counts[bucket] += 1;
+        histogram.sum += seconds;
+    }
+
+    pub fn uploaded(&self, bytes: u64) {
+        self.uploaded_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn downloaded(&self, bytes: u64) {
+        self.downloaded_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn backup_created(&self, bytes: u64) {
+        self.backups_created.fetch_add(1, Ordering::Relaxed);
+        self.backup_bytes.fetch_add(bytes as i64, Ordering::Relaxed);
+    }
+
+    pub fn backup_pruned(&self, bytes: u64) {
+        self.backups_pruned.fetch_add(1, Ordering::Relaxed);
+        self.backup_bytes.fetch_sub(bytes as i64, Ordering::Relaxed);
+    }
+
+    pub fn backup_bytes(&self) -> u64 {
+        self.backup_bytes.load(Ordering::Relaxed).max(0) as u64
+    }
+
+    /// Count a transfer as active until the guard is dropped.
+    pub fn transfer(&'static self, direction: Direction) -> TransferGuard {
+        self.active(direction).fetch_add(1, Ordering::Relaxed);
+        TransferGuard {
+            metrics: self,
+            direction,
+        }
+    }
+
+    fn active(&self, direction: Direction) -> &AtomicI64 {
+        match direction {
+            Direction::Upload => &self.active_uploads,
+            Direction::Download => &self.active_downloads,
+        }
+    }
+
+    /// Add the backups already below `roots`, so the storage gauge does not
+    /// start from zero. Walks every root, so it runs once at start-up.
+    pub fn scan_backups(&self, roots: &[String]) {
+        let mut bytes = 0;
+        for root in roots.iter() {
+            bytes += backup_bytes(Path::new(root));
+        }
+        debug!("found {} bytes of backups below {:?}", bytes, roots);
+        self.backup_bytes.fetch_add(bytes as i64, Ordering::Relaxed);
+    }
+
+    /// The Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP sync_requests_total Responses by route, method and status.\n");
+        out.push_str("# TYPE sync_requests_total counter\n");
+        for ((route, method, status), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "sync_requests_total{{route=\"{}\",method=\"{}\",status=\"{}\"}} {}",
+                escape(route),
+                method,
+                status,
+                count
+            );
+        }
+
+        out.push_str("# HELP sync_request_duration_seconds Time to answer a request by route.\n");
+        out.push_str("# TYPE sync_request_duration_seconds histogram\n");
+        for (route, histogram) in self.durations.lock().unwrap().iter() {
+            let route = escape(route);
+            let mut cumulative = 0;
+            for (i, count) in histogram.counts.iter().enumerate() {
+                cumulative += count;
+                let bound = BUCKETS
+                    .get(i)
+                    .map_or("+Inf".to_string(), |bound| bound.to_string());
+                let _ = writeln!(
+                    out,
+                    "sync_request_duration_seconds_bucket{{route=\"{}\",le=\"{}\"}} {}",
+                    route, bound, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "sync_request_duration_seconds_sum{{route=\"{}\"}} {}",
+                route, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "sync_request_duration_seconds_count{{route=\"{}\"}} {}",
+                route, cumulative
+            );
+        }
+
+        let counters = [
+            (
+                "sync_uploaded_bytes_total",
+                "Bytes of files written by uploads.",
+                self.uploaded_bytes.load(Ordering::Relaxed),
+            ),
+            (
+                "sync_downloaded_bytes_total",
+                "Bytes of files sent by downloads.",
+                self.downloaded_bytes.load(Ordering::Relaxed),
+            ),
+            (
+                "sync_backups_created_total",
+                "Safe mode backups created.",
+                self.backups_created.load(Ordering::Relaxed),
+            ),
+            (
+                "sync_backups_pruned_total",
+                "Backups removed by backup.keep.",
+                self.backups_pruned.load(Ordering::Relaxed),
+            ),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(
+                out,
+                "# HELP {} {}\n# TYPE {} counter\n{} {}",
+                name, help, name, name, value
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP sync_backup_bytes Bytes of backups below the roots.\n# TYPE sync_backup_bytes gauge\nsync_backup_bytes {}",
+            self.backup_bytes()
+        );
+        out.push_str("# HELP sync_active_transfers Uploads and downloads in progress.\n");
+        out.push_str("# TYPE sync_active_transfers gauge\n");
+        for (direction, active) in [
+            ("upload", &self.active_uploads),
+            ("download", &self.active_downloads),
+        ] {
+            let _ = writeln!(
+                out,
+                "sync_active_transfers{{direction=\"{}\"}} {}",
+                direction,
+                active.load(Ordering::Relaxed)
+            );
+        }
+        out
+    }
+}
+
+pub struct TransferGuard {
+    metrics: &'static Metrics,
+    direction: Direction,
+}
+
+impl Drop for TransferGuard {
+    fn drop(&mut self) {
+        self.metrics
+            .active(self.direction)
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Bytes of the backup files in the backup dirs below `dir`.
+fn backup_bytes(dir: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+    let mut bytes = 0;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !entry.file_type().is_ok_and(|t| t.is_dir()) {
+            continue;
+        }
+        if is_backup_dir(&path) {
+            bytes += std::fs::read_dir(&path)
+                .map(|files| {
+                    files
+                        .flatten()
+                        .filter_map(|file| file.metadata().ok())
+                        .map(|meta| meta.len())
+                        .sum::<u64>()
+                })
+                .unwrap_or(0);
+        } else {
+            bytes += backup_bytes(&path);
+        }
+    }
+    bytes
+}
+
+pub async fn metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(METRICS.render())
+}
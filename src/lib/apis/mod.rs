@@ -1,20 +1,51 @@
+pub mod audit;
+pub mod auth;
+pub mod backups;
+pub mod batch;
+pub mod capabilities;
+pub mod delete;
 pub mod download;
+pub mod health;
+pub mod hooks;
+pub mod list;
+pub mod metrics;
 pub mod ping;
+pub mod rate;
+pub mod response;
+pub mod session;
+pub mod stat;
 pub mod upload;
 
 pub mod urls {
     #[macro_export]
     macro_rules! __PING_URL_V1 {
         ($protocol:expr, $addr:expr) => {
-            format!("{}://{}/ping", $protocol, $addr)
+            format!("{}://{}/v1/ping", $protocol, $addr)
         };
     }
     pub use __PING_URL_V1 as PING_URL_V1;
 
+    // Unversioned, like `/metrics` and `/readyz`.
+    #[macro_export]
+    macro_rules! __CAPABILITIES_URL {
+        ($protocol:expr, $addr:expr) => {
+            format!("{}://{}/capabilities", $protocol, $addr)
+        };
+    }
+    pub use __CAPABILITIES_URL as CAPABILITIES_URL;
+
+    #[macro_export]
+    macro_rules! __HEALTHZ_URL {
+        ($protocol:expr, $addr:expr) => {
+            format!("{}://{}/healthz", $protocol, $addr)
+        };
+    }
+    pub use __HEALTHZ_URL as HEALTHZ_URL;
+
     #[macro_export]
     macro_rules! __UPLOAD_URL_V1 {
         ($protocol:expr, $addr:expr) => {
-            format!("{}://{}/upload", $protocol, $addr)
+            format!("{}://{}/v1/upload", $protocol, $addr)
         };
     }
     pub use __UPLOAD_URL_V1 as UPLOAD_URL_V1;
@@ -22,8 +53,64 @@ pub mod urls {
     #[macro_export]
     macro_rules! __DOWNLOAD_URL_V1 {
         ($protocol:expr, $addr:expr) => {
-            format!("{}://{}/download", $protocol, $addr)
+            format!("{}://{}/v1/download", $protocol, $addr)
         };
     }
     pub use __DOWNLOAD_URL_V1 as DOWNLOAD_URL_V1;
+
+    #[macro_export]
+    macro_rules! __BATCH_URL_V1 {
+        ($protocol:expr, $addr:expr) => {
+            format!("{}://{}/v1/batch", $protocol, $addr)
+        };
+    }
+    pub use __BATCH_URL_V1 as BATCH_URL_V1;
+
+    #[macro_export]
+    macro_rules! __LIST_URL_V1 {
+        ($protocol:expr, $addr:expr) => {
+            format!("{}://{}/v1/list", $protocol, $addr)
+        };
+    }
+    pub use __LIST_URL_V1 as LIST_URL_V1;
+
+    #[macro_export]
+    macro_rules! __DELETE_URL_V1 {
+        ($protocol:expr, $addr:expr) => {
+            format!("{}://{}/v1/delete", $protocol, $addr)
+        };
+    }
+    pub use __DELETE_URL_V1 as DELETE_URL_V1;
+
+    #[macro_export]
+    macro_rules! __STAT_URL_V1 {
+        ($protocol:expr, $addr:expr) => {
+            format!("{}://{}/v1/stat", $protocol, $addr)
+        };
+    }
+    pub use __STAT_URL_V1 as STAT_URL_V1;
+
+    #[macro_export]
+    macro_rules! __BACKUPS_URL_V1 {
+        ($protocol:expr, $addr:expr) => {
+            format!("{}://{}/v1/backups", $protocol, $addr)
+        };
+    }
+    pub use __BACKUPS_URL_V1 as BACKUPS_URL_V1;
+
+    #[macro_export]
+    macro_rules! __SESSIONS_URL_V1 {
+        ($protocol:expr, $addr:expr) => {
+            format!("{}://{}/v1/sessions", $protocol, $addr)
+        };
+    }
+    pub use __SESSIONS_URL_V1 as SESSIONS_URL_V1;
+
+    #[macro_export]
+    macro_rules! __SESSION_URL_V1 {
+        ($protocol:expr, $addr:expr, $id:expr) => {
+            format!("{}://{}/v1/sessions/{}", $protocol, $addr, $id)
+        };
+    }
+    pub use __SESSION_URL_V1 as SESSION_URL_V1;
 }
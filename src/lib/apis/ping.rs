@@ -1,5 +1,7 @@
 use actix_web::{HttpResponse, Responder, Result};
 
+use crate::apis::response::ApiResponse;
+
 pub async fn ping() -> Result<impl Responder> {
-    Ok(HttpResponse::Ok().body("Ping OK!!!"))
+    Ok(HttpResponse::Ok().json(ApiResponse::ok("Ping OK!!!")))
 }
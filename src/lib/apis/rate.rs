@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::error::PayloadError;
+use actix_web::http::header;
+use actix_web::web::Bytes;
+use actix_web::{HttpMessage, HttpResponse, ResponseError};
+use futures::{Stream, StreamExt};
+use log::debug;
+
+use crate::apis::auth::authenticate;
+use crate::apis::response::{ApiError, ErrorCode};
+use crate::config::{ServerConfig, SharedConfig};
+use crate::util::throttle::{Bucket, Throttle, THROTTLE_CHUNK};
+
+/// Buckets of clients kept before idle ones, or else the least recently
+/// used one, are dropped.
+const MAX_CLIENTS: usize = 1024;
+
+/// Probes and scrapes, answered even to a client over its request rate.
+const UNLIMITED_ROUTES: [&str; 3] = ["/healthz", "/readyz", "/metrics"];
+
+/// Request rate limits and bandwidth caps of the `[rate]` config, applied to
+/// every request by a middleware in front of the handlers.
+pub struct Limiter {
+    shared: Arc<SharedConfig>,
+    /// Each client's bucket and when it last made a request.
+    clients: Mutex<HashMap<String, (Bucket, Instant)>>,
+    global: Mutex<Option<Arc<Throttle>>>,
+}
+
+impl Limiter {
+    pub fn new(shared: Arc<SharedConfig>) -> Self {
+        Limiter {
+            shared,
+            clients: Mutex::new(HashMap::new()),
+            global: Mutex::new(None),
+        }
+    }
+
+    /// Answer 429 with `Retry-After` when the request's token, or its ip
+    /// without a token, is over `rate.requests_per_sec`. Health checks and
+    /// metrics are never limited.
+    pub fn admit(&self, req: &ServiceRequest) -> Result<(), HttpResponse> {
+        let cfg = self.shared.current();
+        let Some(rate) = cfg.rate.requests_per_sec else {
+            return Ok(());
+        };
+        if UNLIMITED_ROUTES.contains(&req.path()) {
+            return Ok(());
+        }
+        let burst = cfg.rate.burst.unwrap_or(rate.max(1.0));
+        let client = match authenticate(req.request(), &cfg) {
+            Ok(identity) if identity.token.is_some() => format!("token {}", identity.name()),
+            _ => format!(
+                "ip {}",
+                req.peer_addr()
+                    .map(|addr| addr.ip().to_string())
+                    .unwrap_or_default()
+            ),
+        };
+
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= MAX_CLIENTS {
+            clients.retain(|_, (bucket, _)| !bucket.is_full(rate, burst));
+        }
+        if clients.len() >= MAX_CLIENTS && !clients.contains_key(&client) {
+            // Every client is active, make room for this one.
+            let oldest = clients
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(name, _)| name.clone());
+            if let Some(oldest) = oldest {
+                clients.remove(&oldest);
+            }
+        }
+        let (bucket, used) = clients
+            .entry(client.clone())
+            .or_insert_with(|| (Bucket::new(burst), Instant::now()));
+        *used = Instant::now();
+        let wait = match bucket.try_take(1.0, rate, burst) {
+            Ok(()) => return Ok(()),
+            Err(wait) => wait,
+        };
+        debug!("rate limit {}, retry in {:?}", client, wait);
+        let mut response = ApiError::new(
+            ErrorCode::TooManyRequests,
+            format!("more than {} requests per second", rate),
+        )
+        .error_response();
+        response.headers_mut().insert(
+            header::RETRY_AFTER,
+            header::HeaderValue::from(wait.as_secs_f64().ceil().max(1.0) as u64),
+        );
+        Err(response)
+    }
+
+    /// Throttle the request body, and return the throttles its response must share.
+    pub fn throttle_request(&self, req: &mut ServiceRequest) -> Vec<Arc<Throttle>> {
+        let cfg = self.shared.current();
+        let throttles = self.throttles(&cfg);
+        if throttles.is_empty() {
+            return throttles;
+        }
+        let shared = throttles.clone();
+        let payload = req.take_payload().then(move |chunk| {
+            let wait = match &chunk {
+                Ok(bytes) => delay(&shared, bytes.len()),
+                Err(_) => Duration::ZERO,
+            };
+            async move {
+                if !wait.is_zero() {
+                    tokio::time::sleep(wait).await;
+                }
+                chunk
+            }
+        });
+        let payload: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> = Box::pin(payload);
+        req.set_payload(Payload::from(payload));
+        throttles
+    }
+
+    fn throttles(&self, cfg: &ServerConfig) -> Vec<Arc<Throttle>> {
+        let mut throttles = Vec::new();
+        let mut global = self.global.lock().unwrap();
+        match cfg.rate.global_bytes_per_sec {
+            // A reload may have changed the rate.
+            Some(rate) => {
+                if global
+                    .as_ref()
+                    .is_none_or(|throttle| throttle.rate() != rate)
+                {
+                    *global = Some(Arc::new(Throttle::new(rate)));
+                }
+                throttles.extend(global.clone());
+            }
+            None => *global = None,
+        }
+        if let Some(rate) = cfg.rate.connection_bytes_per_sec {
+            throttles.push(Arc::new(Throttle::new(rate)));
+        }
+        throttles
+    }
+}
+
+/// Throttle a response body with the throttles of its request.
+pub fn throttle_response<B>(
+    res: ServiceResponse<B>,
+    throttles: Vec<Arc<Throttle>>,
+) -> ServiceResponse<BoxBody>
+where
+    B: MessageBody + 'static,
+{
+    if throttles.is_empty() {
+        return res.map_into_boxed_body();
+    }
+    res.map_into_boxed_body().map_body(|_, body| {
+        BoxBody::new(ThrottledBody {
+            body,
+            pending: Bytes::new(),
+            sleep: None,
+            throttles,
+        })
+    })
+}
+
+fn delay(throttles: &[Arc<Throttle>], n: usize) -> Duration {
+    throttles
+        .iter()
+        .map(|throttle| throttle.delay(n))
+        .max()
+        .unwrap_or_default()
+}
+
+/// A response body passed on in small pieces, pausing to stay under its throttles.
+struct ThrottledBody {
+    body: BoxBody,
+    pending: Bytes,
+    sleep: Option<Pin<Box<tokio::time::Sleep>>>,
+    throttles: Vec<Arc<Throttle>>,
+}
+
+impl MessageBody for ThrottledBody {
+    type Error = Box<dyn std::error::Error>;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        if let Some(sleep) = this.sleep.as_mut() {
+            ready!(sleep.as_mut().poll(cx));
+            this.sleep = None;
+        }
+        while this.pending.is_empty() {
+            match ready!(Pin::new(&mut this.body).poll_next(cx)) {
+                Some(Ok(chunk)) => this.pending = chunk,
+                other => return Poll::Ready(other),
+            }
+        }
+        let chunk = this
+            .pending
+            .split_to(this.pending.len().min(THROTTLE_CHUNK));
+        let wait = delay(&this.throttles, chunk.len());
+        if !wait.is_zero() {
+            this.sleep = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+        Poll::Ready(Some(Ok(chunk)))
+    }
+}
@@ -0,0 +1,254 @@
+use std::fmt::Display;
+
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+
+use crate::apis::capabilities::Capabilities;
+use crate::apis::health::Health;
+use crate::apis::hooks::HookOutcome;
+
+/// Machine-readable error code carried by every failed response.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidArgument,
+    Unauthorized,
+    NotFound,
+    PermissionDenied,
+    Conflict,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    TooManyRequests,
+    InsufficientStorage,
+    /// A validate hook rejected the new content.
+    ValidationFailed,
+    /// A post hook failed and the previous version was put back.
+    RolledBack,
+    Internal,
+    Unavailable,
+}
+
+impl ErrorCode {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::InvalidArgument => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::PermissionDenied => StatusCode::FORBIDDEN,
+            Self::Conflict => StatusCode::CONFLICT,
+            Self::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            Self::InsufficientStorage => StatusCode::INSUFFICIENT_STORAGE,
+            Self::ValidationFailed | Self::RolledBack => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    /// Best-effort mapping used when a response body is not an envelope.
+    pub fn from_status(status: u16) -> Self {
+        let status = StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        match status {
+            StatusCode::UNAUTHORIZED => Self::Unauthorized,
+            StatusCode::NOT_FOUND => Self::NotFound,
+            StatusCode::FORBIDDEN => Self::PermissionDenied,
+            StatusCode::CONFLICT => Self::Conflict,
+            StatusCode::PAYLOAD_TOO_LARGE => Self::PayloadTooLarge,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => Self::UnsupportedMediaType,
+            StatusCode::TOO_MANY_REQUESTS => Self::TooManyRequests,
+            StatusCode::INSUFFICIENT_STORAGE => Self::InsufficientStorage,
+            StatusCode::UNPROCESSABLE_ENTITY => Self::ValidationFailed,
+            StatusCode::SERVICE_UNAVAILABLE => Self::Unavailable,
+            s if s.is_client_error() => Self::InvalidArgument,
+            _ => Self::Internal,
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::InvalidArgument => "invalid_argument",
+            Self::Unauthorized => "unauthorized",
+            Self::NotFound => "not_found",
+            Self::PermissionDenied => "permission_denied",
+            Self::Conflict => "conflict",
+            Self::PayloadTooLarge => "payload_too_large",
+            Self::UnsupportedMediaType => "unsupported_media_type",
+            Self::TooManyRequests => "too_many_requests",
+            Self::InsufficientStorage => "insufficient_storage",
+            Self::ValidationFailed => "validation_failed",
+            Self::RolledBack => "rolled_back",
+            Self::Internal => "internal",
+            Self::Unavailable => "unavailable",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Envelope returned by every JSON api.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ApiResponse {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<ErrorCode>,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changed: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backup: Option<String>,
+    /// Octal permission bits, e.g. `"0644"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+    /// Whether the caller may write the path, answered by `/stat`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub writable: Option<bool>,
+    /// Id of an upload session.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Byte ranges `[start, end)` an upload session has received.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ranges: Vec<[u64; 2]>,
+    /// Answer of `/capabilities`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Capabilities>,
+    /// Checks of `/healthz` and `/readyz`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health: Option<Health>,
+    /// Hooks run by a write, see `config::HookConfig`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hooks: Vec<HookOutcome>,
+    /// Per-file results of a batch request.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<ApiResponse>,
+}
+
+impl ApiResponse {
+    pub fn ok(message: impl Into<String>) -> Self {
+        ApiResponse {
+            ok: true,
+            message: message.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub fn into_http(self) -> HttpResponse {
+        let status = self.code.map(|c| c.status()).unwrap_or(StatusCode::OK);
+        HttpResponse::build(status).json(self)
+    }
+}
+
+/// Error shared by the server handlers and the client parser.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub path: Option<String>,
+    /// Hooks that ran before the error, e.g. the validation that failed.
+    pub hooks: Vec<HookOutcome>,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ApiError {
+            code,
+            message: message.into(),
+            path: None,
+            hooks: Vec::new(),
+        }
+    }
+
+    pub fn invalid(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::InvalidArgument, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::NotFound, message)
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Conflict, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Internal, message)
+    }
+
+    pub fn with_path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Wrap an io error, keeping `context` as the message prefix.
+    pub fn io(context: &str, err: std::io::Error) -> Self {
+        let code = match err.kind() {
+            std::io::ErrorKind::NotFound => ErrorCode::NotFound,
+            std::io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+            std::io::ErrorKind::AlreadyExists => ErrorCode::Conflict,
+            std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded => {
+                ErrorCode::InsufficientStorage
+            }
+            _ => ErrorCode::Internal,
+        };
+        Self::new(code, format!("{}: {}", context, err))
+    }
+
+    /// Parse an error out of a response body, falling back to the status.
+    pub fn from_response(status: u16, body: &str) -> Self {
+        match serde_json::from_str::<ApiResponse>(body) {
+            Ok(resp) if resp.code.is_some() => ApiError {
+                code: resp.code.unwrap(),
+                message: resp.message,
+                path: resp.path,
+                hooks: resp.hooks,
+            },
+            _ => Self::new(ErrorCode::from_status(status), body.trim().to_string()),
+        }
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "{}: {} ({})", self.code, self.message, path),
+            None => write!(f, "{}: {}", self.code, self.message),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.code.status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        ApiResponse::from(self.clone()).into_http()
+    }
+}
+
+impl From<ApiError> for ApiResponse {
+    fn from(err: ApiError) -> Self {
+        ApiResponse {
+            ok: false,
+            code: Some(err.code),
+            message: err.message,
+            path: err.path,
+            hooks: err.hooks,
+            ..Default::default()
+        }
+    }
+}
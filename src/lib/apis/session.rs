@@ -0,0 +1,467 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Local;
+use futures::StreamExt;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+use crate::apis::audit::{AuditAction, AuditEntry};
+use crate::apis::auth::{authenticate, Identity};
+use crate::apis::metrics::{Direction, METRICS};
+use crate::apis::response::{ApiError, ApiResponse, ErrorCode};
+use crate::apis::upload::{content_md5, ensure_free_space, validate_upload_args, write_form};
+use crate::config::{Access, ServerConfig, SharedConfig};
+use crate::util::schema::{parse_mode, Action, UploadForm};
+
+static SESSION_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// Serializes every read-modify-write of a session's metadata.
+static SESSION_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Sessions being written to their target, which must not change meanwhile.
+static COMMITTING: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Marks a session as being committed until dropped, so one commit writing
+/// it, with its hooks, does not hold up every other session.
+struct CommitLock {
+    id: String,
+}
+
+impl CommitLock {
+    fn take(id: &str) -> Result<Self, ApiError> {
+        if !COMMITTING.lock().unwrap().insert(id.to_string()) {
+            return Err(Self::busy(id));
+        }
+        Ok(CommitLock { id: id.to_string() })
+    }
+
+    fn held(id: &str) -> bool {
+        COMMITTING.lock().unwrap().contains(id)
+    }
+
+    /// Fail with 409 while the session is being committed.
+    fn check(id: &str) -> Result<(), ApiError> {
+        match Self::held(id) {
+            true => Err(Self::busy(id)),
+            false => Ok(()),
+        }
+    }
+
+    fn busy(id: &str) -> ApiError {
+        ApiError::conflict(format!("upload session {} is being committed", id))
+    }
+}
+
+impl Drop for CommitLock {
+    fn drop(&mut self) {
+        COMMITTING.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateReq {
+    target_file_path: String,
+    #[serde(default)]
+    action: Option<String>,
+    #[serde(default)]
+    mode: Option<String>,
+    size: u64,
+    hash: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChunkQuery {
+    offset: u64,
+}
+
+/// A resumable upload, stored as `<id>.json` next to its data in `<id>.part`.
+#[derive(Serialize, Deserialize, Debug)]
+struct Session {
+    id: String,
+    owner: String,
+    target_file_path: String,
+    action: String,
+    mode: Option<String>,
+    size: u64,
+    hash: String,
+    /// Received byte ranges `[start, end)`, sorted and merged.
+    ranges: Vec<[u64; 2]>,
+    /// Unix seconds of the last change, sessions expire from it.
+    updated: i64,
+}
+
+impl Session {
+    fn meta_path(dir: &std::path::Path, id: &str) -> PathBuf {
+        dir.join(format!("{}.json", id))
+    }
+
+    fn part_path(dir: &std::path::Path, id: &str) -> PathBuf {
+        dir.join(format!("{}.part", id))
+    }
+
+    async fn load(dir: &std::path::Path, id: &str) -> Result<Self, ApiError> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(ApiError::invalid("invalid session id"));
+        }
+        let content = match tokio::fs::read(Self::meta_path(dir, id)).await {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err(ApiError::not_found(format!("no upload session {}", id)))
+            }
+            Err(err) => return Err(ApiError::io("read session err", err)),
+        };
+        serde_json::from_slice(&content)
+            .map_err(|err| ApiError::internal(format!("parse session {} err: {}", id, err)))
+    }
+
+    /// Write the metadata through a tmp file so a crash never leaves half of it.
+    async fn save(&mut self, dir: &std::path::Path) -> Result<(), ApiError> {
+        self.updated = Local::now().timestamp();
+        let content =
+            serde_json::to_vec(self).map_err(|err| ApiError::internal(err.to_string()))?;
+        let tmp = dir.join(format!("{}.json.tmp", self.id));
+        tokio::fs::write(&tmp, content)
+            .await
+            .map_err(|err| ApiError::io("write session err", err))?;
+        tokio::fs::rename(&tmp, Self::meta_path(dir, &self.id))
+            .await
+            .map_err(|err| ApiError::io("write session err", err))
+    }
+
+    async fn remove(&self, dir: &std::path::Path) {
+        for path in [
+            Self::meta_path(dir, &self.id),
+            Self::part_path(dir, &self.id),
+        ] {
+            if let Err(err) = tokio::fs::remove_file(&path).await {
+                warn!("remove session file {:?} err: {}", path, err);
+            }
+        }
+    }
+
+    fn check_owner(&self, identity: &Identity) -> Result<(), ApiError> {
+        if self.owner != identity.name() {
+            return Err(ApiError::new(
+                ErrorCode::PermissionDenied,
+                format!("upload session {} belongs to another token", self.id),
+            ));
+        }
+        Ok(())
+    }
+
+    fn merge(&mut self, start: u64, end: u64) {
+        self.ranges.push([start, end]);
+        self.ranges.sort();
+        let mut merged: Vec<[u64; 2]> = Vec::with_capacity(self.ranges.len());
+        for range in self.ranges.drain(..) {
+            match merged.last_mut() {
+                Some(last) if range[0] <= last[1] => last[1] = last[1].max(range[1]),
+                _ => merged.push(range),
+            }
+        }
+        self.ranges = merged;
+    }
+
+    fn is_complete(&self) -> bool {
+        self.size == 0 || self.ranges.first() == Some(&[0, self.size])
+    }
+
+    fn into_response(self, message: &str) -> ApiResponse {
+        ApiResponse {
+            id: Some(self.id),
+            bytes: Some(self.size),
+            hash: Some(self.hash),
+            ranges: self.ranges,
+            ..ApiResponse::ok(message).with_path(self.target_file_path)
+        }
+    }
+}
+
+async fn staging_dir(cfg: &ServerConfig) -> Result<PathBuf, ApiError> {
+    let dir = cfg.sessions.dir();
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .map_err(|err| ApiError::io("create sessions dir err", err))?;
+    Ok(dir)
+}
+
+/// Start an upload session, or resume the one the same token already started
+/// for the same target and content.
+pub async fn create_session(
+    http_req: HttpRequest,
+    req: web::Json<CreateReq>,
+    shared: web::Data<SharedConfig>,
+) -> Result<HttpResponse, ApiError> {
+    let cfg = shared.current();
+    let identity = authenticate(&http_req, &cfg)?;
+    let action = Action::from_str(req.action.as_deref().unwrap_or_default()).unwrap();
+    let form = UploadForm {
+        action,
+        target_file_path: req.target_file_path.clone(),
+        ..Default::default()
+    };
+    validate_upload_args(&form).map_err(|err| {
+        ApiError::invalid(format!("validate form err: {}", err)).with_path(&req.target_file_path)
+    })?;
+    identity.check(&cfg, &req.target_file_path, Access::Write)?;
+    if let Some(mode) = &req.mode {
+        parse_mode(mode).ok_or_else(|| ApiError::invalid("mode is not an octal number"))?;
+    }
+    if let Some(limit) = identity.max_upload_bytes(&cfg) {
+        if req.size > limit {
+            return Err(ApiError::new(
+                ErrorCode::PayloadTooLarge,
+                format!("upload exceeds the limit of {} bytes", limit),
+            )
+            .with_path(&req.target_file_path));
+        }
+    }
+
+    purge_expired(&cfg).await;
+    let dir = staging_dir(&cfg).await?;
+    ensure_free_space(&cfg, &dir, req.size).map_err(|err| err.with_path(&req.target_file_path))?;
+    let _guard = SESSION_LOCK.lock().await;
+    let mut entries = tokio::fs::read_dir(&dir)
+        .await
+        .map_err(|err| ApiError::io("read sessions dir err", err))?;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some(id) = name.strip_suffix(".json") else {
+            continue;
+        };
+        let Ok(session) = Session::load(&dir, id).await else {
+            continue;
+        };
+        if session.owner == identity.name()
+            && !CommitLock::held(id)
+            && session.target_file_path == req.target_file_path
+            && session.action == action.to_string()
+            && session.mode == req.mode
+            && session.size == req.size
+            && session.hash == req.hash
+        {
+            debug!("resume upload session {} by {}", id, identity.name());
+            return Ok(session.into_response("session resumed").into_http());
+        }
+    }
+
+    let id = format!(
+        "{}-{}-{}",
+        Local::now().format("%Y%m%d%H%M%S"),
+        std::process::id(),
+        SESSION_SEQ.fetch_add(1, Ordering::Relaxed)
+    );
+    let part = tokio::fs::File::create(Session::part_path(&dir, &id))
+        .await
+        .map_err(|err| ApiError::io("create session err", err))?;
+    part.set_len(req.size)
+        .await
+        .map_err(|err| ApiError::io("create session err", err))?;
+    let mut session = Session {
+        id,
+        owner: identity.name().to_string(),
+        target_file_path: req.target_file_path.clone(),
+        action: action.to_string(),
+        mode: req.mode.clone(),
+        size: req.size,
+        hash: req.hash.clone(),
+        ranges: Vec::new(),
+        updated: 0,
+    };
+    session.save(&dir).await?;
+    info!(
+        "upload session {} by {}: {} ({} bytes)",
+        session.id,
+        identity.name(),
+        session.target_file_path,
+        session.size
+    );
+    Ok(session.into_response("session created").into_http())
+}
+
+/// The byte ranges a session has received so far.
+pub async fn session_status(
+    http_req: HttpRequest,
+    id: web::Path<String>,
+    shared: web::Data<SharedConfig>,
+) -> Result<HttpResponse, ApiError> {
+    let cfg = shared.current();
+    let identity = authenticate(&http_req, &cfg)?;
+    let session = Session::load(&cfg.sessions.dir(), &id).await?;
+    session.check_owner(&identity)?;
+    Ok(session.into_response("session status").into_http())
+}
+
+/// Write the request body at `?offset=` of the session's data.
+pub async fn put_chunk(
+    http_req: HttpRequest,
+    id: web::Path<String>,
+    query: web::Query<ChunkQuery>,
+    mut payload: web::Payload,
+    shared: web::Data<SharedConfig>,
+) -> Result<HttpResponse, ApiError> {
+    let cfg = shared.current();
+    let identity = authenticate(&http_req, &cfg)?;
+    let _transfer = METRICS.transfer(Direction::Upload);
+    let dir = cfg.sessions.dir();
+    let session = Session::load(&dir, &id).await?;
+    session.check_owner(&identity)?;
+    let path = session.target_file_path.clone();
+    CommitLock::check(&session.id).map_err(|err| err.with_path(&path))?;
+
+    let mut part = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(Session::part_path(&dir, &session.id))
+        .await
+        .map_err(|err| ApiError::io("open session err", err).with_path(&path))?;
+    part.seek(std::io::SeekFrom::Start(query.offset))
+        .await
+        .map_err(|err| ApiError::io("seek session err", err).with_path(&path))?;
+    let mut end = query.offset;
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| ApiError::invalid(format!("read chunk err: {}", e)))?;
+        end += chunk.len() as u64;
+        if end > session.size {
+            return Err(ApiError::invalid(format!(
+                "chunk ends past the size of {} bytes",
+                session.size
+            ))
+            .with_path(&path));
+        }
+        part.write_all(&chunk)
+            .await
+            .map_err(|err| ApiError::io("write session err", err).with_path(&path))?;
+    }
+    part.flush()
+        .await
+        .map_err(|err| ApiError::io("write session err", err).with_path(&path))?;
+    part.sync_data()
+        .await
+        .map_err(|err| ApiError::io("write session err", err).with_path(&path))?;
+
+    // Reload under the lock, a concurrent chunk may have changed the ranges.
+    let _guard = SESSION_LOCK.lock().await;
+    let mut session = Session::load(&dir, &session.id).await?;
+    if end > query.offset {
+        session.merge(query.offset, end);
+    }
+    session.save(&dir).await?;
+    Ok(session.into_response("chunk received").into_http())
+}
+
+/// Verify a complete session and write it to its target like `/upload` would.
+pub async fn commit_session(
+    http_req: HttpRequest,
+    id: web::Path<String>,
+    shared: web::Data<SharedConfig>,
+) -> Result<HttpResponse, ApiError> {
+    let cfg = shared.current();
+    let identity = authenticate(&http_req, &cfg)?;
+    let dir = cfg.sessions.dir();
+    let (session, _commit) = {
+        let _guard = SESSION_LOCK.lock().await;
+        let session = Session::load(&dir, &id).await?;
+        session.check_owner(&identity)?;
+        if !session.is_complete() {
+            return Err(ApiError::conflict(format!(
+                "session is missing data, received {:?} of {} bytes",
+                session.ranges, session.size
+            ))
+            .with_path(&session.target_file_path));
+        }
+        let commit = CommitLock::take(&session.id)
+            .map_err(|err| err.with_path(&session.target_file_path))?;
+        (session, commit)
+    };
+    let path = session.target_file_path.clone();
+
+    let content = tokio::fs::read(Session::part_path(&dir, &session.id))
+        .await
+        .map_err(|err| ApiError::io("read session err", err).with_path(&path))?;
+    if content_md5(&content) != session.hash {
+        session.remove(&dir).await;
+        return Err(
+            ApiError::invalid("content does not match the hash of the session").with_path(&path),
+        );
+    }
+    let form = UploadForm {
+        action: Action::from_str(&session.action).unwrap(),
+        content,
+        target_file_path: path.clone(),
+        mode: session.mode.as_deref().and_then(parse_mode),
+    };
+    // Checks the acl again, it may have changed since the session started.
+    let result = write_form(&form, &http_req, &identity, &cfg).await;
+    AuditEntry::new(&http_req, &identity, AuditAction::Upload, &path)
+        .with_write(result.as_ref().ok())
+        .record(&cfg, &result);
+    let outcome = result?;
+    session.remove(&dir).await;
+    debug!(
+        "commit upload session {} by {}",
+        session.id,
+        identity.name()
+    );
+
+    let message = if outcome.changed {
+        "upload successfully"
+    } else {
+        "file is not changed"
+    };
+    Ok(ApiResponse {
+        id: Some(session.id),
+        ..outcome.into_response(message, &path)
+    }
+    .into_http())
+}
+
+/// Abort a session and drop what it received.
+pub async fn delete_session(
+    http_req: HttpRequest,
+    id: web::Path<String>,
+    shared: web::Data<SharedConfig>,
+) -> Result<HttpResponse, ApiError> {
+    let cfg = shared.current();
+    let identity = authenticate(&http_req, &cfg)?;
+    let dir = cfg.sessions.dir();
+    let _guard = SESSION_LOCK.lock().await;
+    let session = Session::load(&dir, &id).await?;
+    session.check_owner(&identity)?;
+    CommitLock::check(&session.id).map_err(|err| err.with_path(&session.target_file_path))?;
+    session.remove(&dir).await;
+    Ok(ApiResponse::ok("session aborted")
+        .with_path(session.target_file_path)
+        .into_http())
+}
+
+/// Remove sessions that received nothing for `sessions.expiry_secs`.
+pub async fn purge_expired(cfg: &ServerConfig) {
+    let dir = cfg.sessions.dir();
+    let Ok(mut entries) = tokio::fs::read_dir(&dir).await else {
+        return;
+    };
+    let deadline = Local::now().timestamp() - cfg.sessions.expiry_secs as i64;
+    let _guard = SESSION_LOCK.lock().await;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some(id) = name.strip_suffix(".json") else {
+            continue;
+        };
+        match Session::load(&dir, id).await {
+            Ok(session) if session.updated < deadline && !CommitLock::held(id) => {
+                info!(
+                    "upload session {} expired: {}",
+                    session.id, session.target_file_path
+                );
+                session.remove(&dir).await;
+            }
+            Ok(_) => {}
+            Err(err) => warn!("skip session {}: {}", id, err),
+        }
+    }
+}
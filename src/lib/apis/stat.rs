@@ -0,0 +1,91 @@
+use std::path;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::debug;
+use serde::Deserialize;
+
+use crate::apis::auth::authenticate;
+use crate::apis::response::{ApiError, ApiResponse};
+use crate::apis::upload::{backup_location, content_md5};
+use crate::config::{Access, SharedConfig};
+
+#[derive(Deserialize)]
+pub struct StatReq {
+    file_paths: Vec<String>,
+}
+
+/// Describe files without touching them, so a client can tell what a push,
+/// pull or delete would do. Each path gets its own entry, missing ones included,
+/// telling whether the caller could write it.
+pub async fn stat_files(
+    http_req: HttpRequest,
+    req: web::Json<StatReq>,
+    shared: web::Data<SharedConfig>,
+) -> Result<HttpResponse, ApiError> {
+    if req.file_paths.is_empty() {
+        return Err(ApiError::invalid("file_paths is empty"));
+    }
+    let cfg = shared.current();
+    let identity = authenticate(&http_req, &cfg)?;
+    debug!(
+        "stat by {}: {} files",
+        identity.name(),
+        req.file_paths.len()
+    );
+
+    let mut files = Vec::with_capacity(req.file_paths.len());
+    for file_path in req.file_paths.iter() {
+        let entry = match identity.check(&cfg, file_path, Access::Read) {
+            Err(err) => err.into(),
+            Ok(_) => ApiResponse {
+                writable: Some(identity.check(&cfg, file_path, Access::Write).is_ok()),
+                ..stat_one(file_path)
+                    .await
+                    .unwrap_or_else(|err| err.with_path(file_path).into())
+            },
+        };
+        files.push(entry);
+    }
+
+    Ok(ApiResponse {
+        files,
+        ..ApiResponse::ok("stat successfully")
+    }
+    .into_http())
+}
+
+async fn stat_one(file_path: &str) -> Result<ApiResponse, ApiError> {
+    let target = path::Path::new(file_path);
+    let (dot_backup_dir, _) = backup_location(target)?;
+    if !target.exists() {
+        return Err(ApiError::not_found("not found path"));
+    }
+    if target.is_dir() {
+        return Err(ApiError::conflict("path is a directory"));
+    }
+
+    let content = tokio::fs::read(target)
+        .await
+        .map_err(|err| ApiError::io("read file err", err))?;
+    let metadata = tokio::fs::metadata(target)
+        .await
+        .map_err(|err| ApiError::io("stat file err", err))?;
+    #[cfg(unix)]
+    let mode = {
+        use std::os::unix::fs::PermissionsExt;
+        Some(format!("{:04o}", metadata.permissions().mode() & 0o7777))
+    };
+    #[cfg(not(unix))]
+    let mode = {
+        let _ = metadata;
+        None
+    };
+    Ok(ApiResponse {
+        bytes: Some(content.len() as u64),
+        hash: Some(content_md5(&content)),
+        mode,
+        // Where a safe write or delete would back the file up.
+        backup: Some(dot_backup_dir),
+        ..ApiResponse::ok("stat successfully").with_path(file_path)
+    })
+}
@@ -1,41 +1,82 @@
 use actix_multipart::{Field, Multipart};
-use actix_web::{web, HttpRequest, HttpResponse, Responder, Result};
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
 use chrono::Local;
 use futures::StreamExt;
 use log::{debug, error, warn};
 use std::{path, str::FromStr};
 
-use crate::util::schema::{Action, UploadForm};
+use crate::apis::audit::{AuditAction, AuditEntry};
+use crate::apis::auth::{authenticate, Identity};
+use crate::apis::hooks::{self, HookOutcome, HookStage, Snapshot, HOOK_LOCK};
+use crate::apis::metrics::{Direction, METRICS};
+use crate::apis::response::{ApiError, ApiResponse, ErrorCode};
+use crate::config::{Access, ServerConfig, SharedConfig};
+use crate::util::compress::Encoding;
+use crate::util::file::available_space;
+use crate::util::schema::{parse_mode, Action, UploadForm};
 
-pub async fn upload(req: HttpRequest, bytes: web::Payload) -> Result<impl Responder> {
+/// What a write did to the target file.
+#[derive(Debug, Default)]
+pub struct WriteOutcome {
+    pub bytes: u64,
+    pub hash: String,
+    /// Hash of the content replaced, none for a new file.
+    pub old_hash: Option<String>,
+    pub changed: bool,
+    pub backup: Option<String>,
+    pub hooks: Vec<HookOutcome>,
+}
+
+impl WriteOutcome {
+    pub fn into_response(self, message: &str, path: &str) -> ApiResponse {
+        ApiResponse {
+            bytes: Some(self.bytes),
+            hash: Some(self.hash),
+            changed: Some(self.changed),
+            backup: self.backup,
+            hooks: self.hooks,
+            ..ApiResponse::ok(message).with_path(path)
+        }
+    }
+}
+
+pub async fn upload(
+    req: HttpRequest,
+    bytes: web::Payload,
+    shared: web::Data<SharedConfig>,
+) -> std::result::Result<HttpResponse, ApiError> {
+    let cfg = shared.current();
+    let identity = authenticate(&req, &cfg)?;
+    let _transfer = METRICS.transfer(Direction::Upload);
+    let mut budget = UploadBudget::new(&req, identity.max_upload_bytes(&cfg))?;
     let mut multipart = Multipart::new(req.headers(), bytes);
 
     // parse multipart
     let mut form = UploadForm::default();
-    // let mut upload_file = fs::File::create(format!("1.txt")).unwrap();
     while let Some(chunk) = multipart.next().await {
-        let mut chunk = chunk?;
+        let mut chunk =
+            chunk.map_err(|e| ApiError::invalid(format!("read multipart err: {}", e)))?;
 
         let content_disposition = chunk.content_disposition().clone();
         let key = content_disposition.get_name().unwrap_or("");
 
-        let value = read_content_disposition(&mut chunk).await;
-        if value.is_err() {
-            error!("read_content_disposition err: {}", value.err().unwrap());
-            return Ok(HttpResponse::InternalServerError()
-                .body("read content disposition err".to_string()));
-        }
-        let value = value.unwrap();
+        let value = read_content_disposition(&mut chunk, &mut budget).await?;
 
         match key {
             "action" => {
-                form.action = Action::from_str(&value).unwrap();
+                form.action = Action::from_str(&String::from_utf8_lossy(&value)).unwrap();
             }
             "file" => {
                 form.content = value;
             }
             "target_file_path" => {
-                form.target_file_path = value;
+                form.target_file_path = String::from_utf8_lossy(&value).to_string();
+            }
+            "mode" => {
+                form.mode = Some(
+                    parse_mode(&String::from_utf8_lossy(&value))
+                        .ok_or_else(|| ApiError::invalid("mode is not an octal number"))?,
+                );
             }
             _ => {
                 warn!("unknown action '{}'", key);
@@ -43,97 +84,394 @@ pub async fn upload(req: HttpRequest, bytes: web::Payload) -> Result<impl Respon
         }
     }
 
-    if let Err(err) = validate_upload_args(&form) {
-        return Ok(HttpResponse::BadRequest().body(format!("validate form err: {}", err)));
+    debug!("upload by {}: {}", identity.name(), form.target_file_path);
+    let result = write_form(&form, &req, &identity, &cfg).await;
+    AuditEntry::new(&req, &identity, AuditAction::Upload, &form.target_file_path)
+        .with_write(result.as_ref().ok())
+        .record(&cfg, &result);
+    let outcome = result?;
+
+    let message = if outcome.changed {
+        "upload successfully"
+    } else {
+        "file is not changed"
+    };
+    Ok(outcome
+        .into_response(message, &form.target_file_path)
+        .into_http())
+}
+
+/// Validate and check an uploaded file, run its validate hooks on the new
+/// content if it changes, then write it and its mode and run its post hooks.
+/// A failed post hook rolls the write back.
+pub(crate) async fn write_form(
+    form: &UploadForm,
+    req: &HttpRequest,
+    identity: &Identity,
+    cfg: &ServerConfig,
+) -> std::result::Result<WriteOutcome, ApiError> {
+    validate_upload_args(form)
+        .map_err(|err| ApiError::invalid(format!("validate form err: {}", err)))?;
+    identity.check(cfg, &form.target_file_path, Access::Write)?;
+
+    let target = form.target_file_path.as_str();
+    let (_guard, snapshot) = if hooks::hooked(cfg, [target].into_iter()) {
+        let guard = HOOK_LOCK.lock().await;
+        let snapshot = Snapshot::take(path::Path::new(target))
+            .await
+            .map_err(|err| err.with_path(target))?;
+        (Some(guard), Some(snapshot))
+    } else {
+        (None, None)
+    };
+
+    let mut validated = Vec::new();
+    if snapshot
+        .as_ref()
+        .is_some_and(|s| form.action == Action::Force || s.differs(&form.content))
+    {
+        validated = validate_candidate(form, cfg, identity)
+            .await
+            .map_err(|err| err.with_path(target))?;
+        if hooks::rejected(&validated).is_some() {
+            return Err(hooks::validation_error(validated));
+        }
     }
 
-    if let Err(err) = match form.action {
-        Action::Safe => safe_write(&form).await,
-        Action::Force => force_write(&form).await,
-    } {
-        return Ok(HttpResponse::BadRequest().body(format!("write file err: {}", err)));
+    let mut outcome = match form.action {
+        Action::Safe => safe_write(form, cfg).await,
+        Action::Force => force_write(form, cfg).await,
     }
+    .map_err(|err| err.with_path(target))?;
+    apply_mode(target, form.mode)
+        .await
+        .map_err(|err| err.with_path(target))?;
 
-    Ok(HttpResponse::Ok().body("Upload Successfully!".to_string()))
+    if let Some(snapshot) = snapshot.filter(|_| outcome.changed) {
+        outcome.hooks = validated;
+        outcome
+            .hooks
+            .extend(hooks::run(cfg, HookStage::Post, &[target], identity).await);
+        if hooks::post_failed(&outcome.hooks) {
+            return Err(roll_back(target, outcome, snapshot, req, identity, cfg).await);
+        }
+    }
+    METRICS.uploaded(outcome.bytes);
+    Ok(outcome)
 }
 
-async fn safe_write(form: &UploadForm) -> std::result::Result<String, String> {
+/// Write the new content of `form` to a hidden file next to its target and
+/// run the validate hooks on it, so a rejected version never goes live. The
+/// file, and any dir made for it, is removed again.
+async fn validate_candidate(
+    form: &UploadForm,
+    cfg: &ServerConfig,
+    identity: &Identity,
+) -> std::result::Result<Vec<HookOutcome>, ApiError> {
+    let target = path::Path::new(&form.target_file_path);
+    let (Some(dir), Some(name)) = (target.parent(), target.file_name()) else {
+        return Err(ApiError::invalid("target path has no file name"));
+    };
+    let candidate = dir.join(format!(".sync-validate-{}", name.to_string_lossy()));
+    let missing: Vec<path::PathBuf> = dir
+        .ancestors()
+        .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+        .map(path::Path::to_path_buf)
+        .collect();
+
+    let written = async {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .map_err(|err| ApiError::io("create dir err", err))?;
+        tokio::fs::write(&candidate, &form.content)
+            .await
+            .map_err(|err| ApiError::io("write file to validate err", err))?;
+        apply_mode(&candidate, form.mode).await
+    }
+    .await;
+    let result = match written {
+        Ok(()) => {
+            let candidate = candidate.to_string_lossy();
+            Ok(hooks::validate(cfg, &[(&form.target_file_path, &candidate)], identity).await)
+        }
+        Err(err) => Err(err),
+    };
+    if candidate.exists() {
+        if let Err(err) = tokio::fs::remove_file(&candidate).await {
+            warn!("remove validated file {:?} err: {}", candidate, err);
+        }
+    }
+    for dir in missing {
+        if let Err(err) = tokio::fs::remove_dir(&dir).await {
+            warn!("remove dir {:?} made to validate err: {}", dir, err);
+        }
+    }
+    result
+}
+
+/// Put the backup of a safe write back, or the snapshot of a force write, or
+/// remove a new file, after a post hook failed. The post hooks then run again
+/// so the service picks it up.
+async fn roll_back(
+    target: &str,
+    outcome: WriteOutcome,
+    snapshot: Snapshot,
+    req: &HttpRequest,
+    identity: &Identity,
+    cfg: &ServerConfig,
+) -> ApiError {
+    let (result, restored) = match &outcome.backup {
+        Some(backup) => (
+            tokio::fs::copy(backup, target)
+                .await
+                .map(|_| ())
+                .map_err(|err| ApiError::io("restore backup err", err)),
+            format!("restored backup {}", backup),
+        ),
+        None => (
+            snapshot.restore(path::Path::new(target)).await,
+            match outcome.old_hash {
+                Some(_) => "restored the previous version".to_string(),
+                None => "removed the new file".to_string(),
+            },
+        ),
+    };
+    let mut audit = AuditEntry::new(req, identity, AuditAction::Restore, target);
+    audit.old_hash = Some(outcome.hash.clone());
+    audit.new_hash = outcome.old_hash.clone();
+    audit.backup = outcome.backup.clone();
+    audit.record(cfg, &result);
+
+    let mut outcomes = outcome.hooks;
+    if let Err(err) = result {
+        error!("roll back {} after failed post hook err: {}", target, err);
+        return ApiError {
+            hooks: outcomes,
+            message: format!("post hook failed, roll back err: {}", err.message),
+            ..err.with_path(target)
+        };
+    }
+    warn!("post hook failed, rolled back {}: {}", target, restored);
+    outcomes.extend(hooks::run(cfg, HookStage::Rollback, &[target], identity).await);
+    hooks::rollback_error(outcomes, &restored).with_path(target)
+}
+
+/// Set the permission bits requested by the client, if any.
+pub(crate) async fn apply_mode<P: AsRef<path::Path>>(
+    target: P,
+    mode: Option<u32>,
+) -> std::result::Result<(), ApiError> {
+    let Some(mode) = mode else {
+        return Ok(());
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        tokio::fs::set_permissions(target, std::fs::Permissions::from_mode(mode))
+            .await
+            .map_err(|err| ApiError::io("set mode err", err))?;
+    }
+    #[cfg(not(unix))]
+    warn!("ignore mode {:o} for {:?}", mode, target.as_ref());
+    Ok(())
+}
+
+pub fn content_md5(content: &[u8]) -> String {
+    format!("{:x}", md5::compute(content))
+}
+
+/// md5 of the file at `path`, none when it cannot be read.
+pub(crate) async fn file_md5<P: AsRef<path::Path>>(path: P) -> Option<String> {
+    tokio::fs::read(path)
+        .await
+        .ok()
+        .map(|content| content_md5(&content))
+}
+
+pub(crate) async fn safe_write(
+    form: &UploadForm,
+    cfg: &ServerConfig,
+) -> std::result::Result<WriteOutcome, ApiError> {
     let target_path = path::Path::new(&form.target_file_path);
+    let new_md5 = content_md5(&form.content);
+    let mut outcome = WriteOutcome {
+        bytes: form.content.len() as u64,
+        hash: new_md5.clone(),
+        ..Default::default()
+    };
+
+    if target_path.is_dir() {
+        return Err(ApiError::conflict("target path is a directory"));
+    }
+
     // Check md5. Return directly if md5 does not change.
     if target_path.exists() {
-        let old_content = tokio::fs::read_to_string(&target_path)
-            .await
-            .unwrap_or_default();
-        if if_content_md5_equal(&form.content, &old_content) {
+        let old_content = tokio::fs::read(&target_path).await.unwrap_or_default();
+        let old_md5 = content_md5(&old_content);
+        outcome.old_hash = Some(old_md5.clone());
+        if old_md5 == new_md5 {
             debug!(
                 "file({:?}) is not changed.",
                 target_path.file_name().unwrap_or_default()
             );
-            return Ok("file is not changed.".to_string());
+            outcome.bytes = 0;
+            return Ok(outcome);
         }
     }
 
-    let filename = target_path.file_name().unwrap().to_str().unwrap();
-    let filename_without_ext = path::Path::new(filename)
-        .file_stem()
-        .unwrap()
-        .to_str()
-        .unwrap_or(filename);
-    let dir = target_path.parent().unwrap().to_str().unwrap().to_string();
+    let (dot_backup_dir, backup_file) = backup_location(target_path)?;
+    // Room for the new content and the backup copy of the old one.
+    let old_bytes = tokio::fs::metadata(target_path)
+        .await
+        .map(|m| m.len())
+        .unwrap_or(0);
+    ensure_free_space(cfg, target_path, form.content.len() as u64 + old_bytes)?;
 
     // create backup dir
-    let dot_backup_dir = format!("{}/.{}", dir, filename_without_ext);
     debug!("if need safe create backup dir: {}", &dot_backup_dir);
     safe_create_backup_dir(&dot_backup_dir).await?;
 
     // backup file
     if target_path.exists() {
-        let backup_file = format!(
-            "{}/{}.{}",
-            dot_backup_dir,
-            filename,
-            Local::now().format("%Y%m%d_%H%M%S")
-        );
-        if let Err(err) = tokio::fs::copy(target_path, &backup_file).await {
-            return Err(format!("copy backup err: {}", err));
-        }
+        tokio::fs::copy(target_path, &backup_file)
+            .await
+            .map_err(|err| ApiError::io("copy backup err", err))?;
         debug!("backup file({}) ok", backup_file);
+        METRICS.backup_created(old_bytes);
+        prune_backups(&backup_file, cfg.backup.keep).await;
+        outcome.backup = Some(backup_file);
     }
 
     // truncate && write new content
-    if let Err(err) = tokio::fs::write(target_path, &form.content).await {
-        return Err(format!("write file err: {}", err));
-    }
+    tokio::fs::write(target_path, &form.content)
+        .await
+        .map_err(|err| ApiError::io("write file err", err))?;
     debug!("write new content ok, file={:?}", target_path);
 
-    Ok("safe write ok".to_string())
+    outcome.changed = true;
+    Ok(outcome)
+}
+
+/// Fail with 507 when writing `bytes` next to `target` would leave less than
+/// `limits.min_free_bytes` free.
+pub(crate) fn ensure_free_space(
+    cfg: &ServerConfig,
+    target: &path::Path,
+    bytes: u64,
+) -> std::result::Result<(), ApiError> {
+    let Some(min_free) = cfg.limits.min_free_bytes else {
+        return Ok(());
+    };
+    let available =
+        available_space(target).map_err(|err| ApiError::io("check free space err", err))?;
+    if available < bytes.saturating_add(min_free) {
+        return Err(ApiError::new(
+            ErrorCode::InsufficientStorage,
+            format!(
+                "writing {} bytes would leave less than {} bytes free, {} available",
+                bytes, min_free, available
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Backup dir (`.[file_stem]` next to the target) and a fresh timestamped backup file in it.
+pub(crate) fn backup_location(
+    target_path: &path::Path,
+) -> std::result::Result<(String, String), ApiError> {
+    let filename = target_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| ApiError::invalid("target path has no file name"))?;
+    let filename_without_ext = path::Path::new(filename)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(filename);
+    let dir = target_path
+        .parent()
+        .and_then(|dir| dir.to_str())
+        .unwrap_or_default()
+        .to_string();
+
+    let dot_backup_dir = format!("{}/.{}", dir, filename_without_ext);
+    let backup_file = format!(
+        "{}/{}.{}",
+        dot_backup_dir,
+        filename,
+        Local::now().format("%Y%m%d_%H%M%S")
+    );
+    Ok((dot_backup_dir, backup_file))
 }
 
-fn if_content_md5_equal(new_content: &str, old_content: &str) -> bool {
-    let new_md5 = md5::compute(new_content);
-    let old_md5 = md5::compute(old_content);
-    debug!("new_md5 is: {:?}, old_md5 is: {:?}", new_md5, old_md5);
+/// Keep only the newest `keep` backups next to `backup_file`.
+pub(crate) async fn prune_backups(backup_file: &str, keep: Option<usize>) {
+    let Some(keep) = keep else {
+        return;
+    };
+    let backup_path = path::Path::new(backup_file);
+    let (Some(dir), Some(name)) = (backup_path.parent(), backup_path.file_name()) else {
+        return;
+    };
+    // `[filename].[timestamp]`, timestamps sort in creation order
+    let name = name.to_string_lossy();
+    let prefix = match name.rsplit_once('.') {
+        Some((prefix, _)) => format!("{}.", prefix),
+        None => return,
+    };
 
-    new_content == old_content
+    let mut backups = Vec::new();
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let entry_name = entry.file_name().to_string_lossy().to_string();
+        if entry_name.starts_with(&prefix) {
+            backups.push(entry.path());
+        }
+    }
+    backups.sort();
+    let stale = backups.len().saturating_sub(keep);
+    for backup in backups.into_iter().take(stale) {
+        let bytes = tokio::fs::metadata(&backup)
+            .await
+            .map(|m| m.len())
+            .unwrap_or(0);
+        match tokio::fs::remove_file(&backup).await {
+            Ok(_) => {
+                debug!("prune backup {:?}", backup);
+                METRICS.backup_pruned(bytes);
+            }
+            Err(err) => warn!("prune backup {:?} err: {}", backup, err),
+        }
+    }
 }
 
-async fn safe_create_backup_dir(dir_path: &str) -> std::result::Result<(), String> {
+pub(crate) async fn safe_create_backup_dir(dir_path: &str) -> std::result::Result<(), ApiError> {
     let path_obj = path::Path::new(dir_path);
     if path_obj.exists() && !path_obj.is_dir() {
-        return Err(format!("backup dir is conflicted: {}", dir_path));
+        return Err(ApiError::conflict(format!(
+            "backup dir is conflicted: {}",
+            dir_path
+        )));
     }
 
     if !path_obj.exists() {
-        if let Err(err) = tokio::fs::create_dir_all(dir_path).await {
-            return Err(format!("create backup dir err: {}", err));
-        }
+        tokio::fs::create_dir_all(dir_path)
+            .await
+            .map_err(|err| ApiError::io("create backup dir err", err))?;
     }
 
     Ok(())
 }
 
-fn validate_upload_args(form: &UploadForm) -> std::result::Result<(), String> {
-    debug!("{:?}", form);
+pub(crate) fn validate_upload_args(form: &UploadForm) -> std::result::Result<(), String> {
+    debug!(
+        "action={}, target_file_path={}, bytes={}",
+        form.action,
+        form.target_file_path,
+        form.content.len()
+    );
 
     if form.target_file_path.is_empty() {
         return Err("target_file_path is empty".to_string());
@@ -145,26 +483,109 @@ fn validate_upload_args(form: &UploadForm) -> std::result::Result<(), String> {
     Ok(())
 }
 
-async fn read_content_disposition(chunk: &mut Field) -> anyhow::Result<String> {
+/// Bytes a request may still send before it is rejected with 413.
+pub(crate) struct UploadBudget {
+    limit: u64,
+    remaining: Option<u64>,
+}
+
+impl UploadBudget {
+    /// Reject early when `Content-Length` already exceeds `limit`.
+    pub(crate) fn new(
+        req: &HttpRequest,
+        limit: Option<u64>,
+    ) -> std::result::Result<Self, ApiError> {
+        let length = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        if let (Some(limit), Some(length)) = (limit, length) {
+            if length > limit {
+                return Err(Self::too_large(limit));
+            }
+        }
+        Ok(UploadBudget {
+            limit: limit.unwrap_or(u64::MAX),
+            remaining: limit,
+        })
+    }
+
+    fn take(&mut self, n: usize) -> std::result::Result<(), ApiError> {
+        let limit = self.limit;
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining = remaining
+                .checked_sub(n as u64)
+                .ok_or_else(|| Self::too_large(limit))?;
+        }
+        Ok(())
+    }
+
+    fn too_large(limit: u64) -> ApiError {
+        ApiError::new(
+            ErrorCode::PayloadTooLarge,
+            format!("upload exceeds the limit of {} bytes", limit),
+        )
+    }
+}
+
+pub(crate) async fn read_content_disposition(
+    chunk: &mut Field,
+    budget: &mut UploadBudget,
+) -> std::result::Result<Vec<u8>, ApiError> {
     let mut buf = Vec::new();
     while let Some(chunk_content) = chunk.next().await {
         match chunk_content {
             Err(e) => {
-                anyhow::bail!("read chunk err: {}", e);
+                error!("read_content_disposition err: {}", e);
+                return Err(ApiError::invalid(format!("read chunk err: {}", e)));
             }
             Ok(chunk_content) => {
+                budget.take(chunk_content.len())?;
                 buf.extend(chunk_content);
             }
         }
     }
 
-    Ok(String::from_utf8(buf)?)
+    // A part may be compressed on its own, see `util::compress`.
+    let encoding = match chunk.headers().get(header::CONTENT_ENCODING) {
+        None => return Ok(buf),
+        Some(value) => value.to_str().unwrap_or_default().trim().to_string(),
+    };
+    if encoding.is_empty() || encoding.eq_ignore_ascii_case("identity") {
+        return Ok(buf);
+    }
+    let encoding = Encoding::from_str(&encoding)
+        .map_err(|err| ApiError::new(ErrorCode::UnsupportedMediaType, err))?;
+    let limit = budget.limit;
+    web::block(move || encoding.decode(&buf, limit))
+        .await
+        .map_err(|err| ApiError::internal(err.to_string()))?
+        .map_err(|err| match err.kind() {
+            std::io::ErrorKind::FileTooLarge => UploadBudget::too_large(limit),
+            _ => ApiError::invalid(format!("decode {} part err: {}", encoding.as_str(), err)),
+        })
 }
 
-async fn force_write(form: &UploadForm) -> std::result::Result<String, String> {
+pub(crate) async fn force_write(
+    form: &UploadForm,
+    cfg: &ServerConfig,
+) -> std::result::Result<WriteOutcome, ApiError> {
     let target_path = path::Path::new(&form.target_file_path);
-    if let Err(err) = tokio::fs::write(target_path, &form.content).await {
-        return Err(format!("write file err: {}", err));
+    if target_path.is_dir() {
+        return Err(ApiError::conflict("target path is a directory"));
     }
-    Ok("force write ok".to_string())
+    ensure_free_space(cfg, target_path, form.content.len() as u64)?;
+    let old_hash = file_md5(target_path).await;
+    tokio::fs::write(target_path, &form.content)
+        .await
+        .map_err(|err| ApiError::io("write file err", err))?;
+    Ok(WriteOutcome {
+        bytes: form.content.len() as u64,
+        hash: content_md5(&form.content),
+        old_hash,
+        changed: true,
+        backup: None,
+        hooks: Vec::new(),
+    })
 }
@@ -0,0 +1,340 @@
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use log::LevelFilter;
+use serde::Deserialize;
+
+/// `sync-server --config` file. Every section is optional; an empty file
+/// behaves like the server without a config: no auth, no roots, no limits.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields, default)]
+pub struct ServerConfig {
+    /// Addresses to bind, e.g. `["0.0.0.0:9091"]`. Only read at start-up.
+    pub listen: Vec<String>,
+    /// Absolute directories files may be read from or written to. Empty means anywhere.
+    pub roots: Vec<String>,
+    /// Bearer tokens. Empty means requests are not authenticated.
+    pub tokens: Vec<TokenConfig>,
+    pub limits: LimitsConfig,
+    pub backup: BackupConfig,
+    pub sessions: SessionsConfig,
+    pub rate: RateConfig,
+    pub timeouts: TimeoutsConfig,
+    pub audit: AuditConfig,
+    pub log: LogConfig,
+    /// Commands run around writes to matching files, in this order.
+    pub hooks: Vec<HookConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TokenConfig {
+    /// Identity of the client, used in logs.
+    pub name: String,
+    pub token: String,
+    /// Path prefixes this token may access. Empty means every root.
+    #[serde(default)]
+    pub acl: Vec<AclConfig>,
+    /// Lowers `limits.max_upload_bytes` for this token.
+    #[serde(default)]
+    pub max_upload_bytes: Option<u64>,
+    /// Lowers `limits.max_download_bytes` for this token.
+    #[serde(default)]
+    pub max_download_bytes: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AclConfig {
+    pub path: String,
+    #[serde(default)]
+    pub access: Access,
+}
+
+/// `write` implies `read`.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    #[default]
+    Read,
+    Write,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields, default)]
+pub struct LimitsConfig {
+    /// Largest accepted upload body in bytes.
+    pub max_upload_bytes: Option<u64>,
+    /// Largest file a client may download, in bytes.
+    pub max_download_bytes: Option<u64>,
+    /// Writes that would leave less free space on the target's filesystem get 507.
+    pub min_free_bytes: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields, default)]
+pub struct RateConfig {
+    /// Requests per second of each token, or of each client ip without a token. Over it gets 429.
+    pub requests_per_sec: Option<f64>,
+    /// Requests a client may send at once above `requests_per_sec`, `requests_per_sec` when unset.
+    pub burst: Option<f64>,
+    /// Bytes per second of all uploads and downloads together.
+    pub global_bytes_per_sec: Option<u64>,
+    /// Bytes per second of each upload or download.
+    pub connection_bytes_per_sec: Option<u64>,
+}
+
+/// Connection timeouts, only read at start-up.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct TimeoutsConfig {
+    /// Seconds a client has to send the request head; 0 disables it.
+    pub client_request_secs: u64,
+    /// Seconds an idle keep-alive connection stays open; 0 disables keep-alive.
+    pub keep_alive_secs: u64,
+    /// Seconds to wait for a client to close a connection after its response; 0 disables it.
+    pub client_disconnect_secs: u64,
+}
+
+impl Default for TimeoutsConfig {
+    fn default() -> Self {
+        TimeoutsConfig {
+            client_request_secs: 5,
+            keep_alive_secs: 5,
+            client_disconnect_secs: 1,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields, default)]
+pub struct BackupConfig {
+    /// Backups kept per file in safe mode; older ones are pruned. Unlimited when unset.
+    pub keep: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct SessionsConfig {
+    /// Staging dir of resumable uploads, `sync-file-sessions` in the temp dir when unset.
+    pub dir: Option<String>,
+    /// Unfinished uploads are removed after this many seconds without a new chunk.
+    pub expiry_secs: u64,
+}
+
+impl Default for SessionsConfig {
+    fn default() -> Self {
+        SessionsConfig {
+            dir: None,
+            expiry_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+impl SessionsConfig {
+    pub fn dir(&self) -> PathBuf {
+        match &self.dir {
+            Some(dir) => PathBuf::from(dir),
+            None => std::env::temp_dir().join("sync-file-sessions"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct AuditConfig {
+    /// JSON lines file recording every upload, download, delete and restore. Off when unset.
+    pub path: Option<String>,
+    /// The file is rotated to `<path>.1` once it would grow past this; 0 never rotates.
+    pub max_bytes: u64,
+    /// Rotated files kept, `<path>.1` being the newest.
+    pub keep: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            path: None,
+            max_bytes: 100 * 1024 * 1024,
+            keep: 10,
+        }
+    }
+}
+
+/// Commands run with `sh -c` when a file matching `path` is written.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HookConfig {
+    /// Absolute glob, e.g. `/etc/nginx/**/*.conf`. `*` does not match `/`.
+    pub path: String,
+    /// Run with the new content in place. A non-zero exit or timeout puts the
+    /// previous version back and fails the write.
+    #[serde(default)]
+    pub validate: Option<String>,
+    /// Run once the write is kept, e.g. a reload.
+    #[serde(default)]
+    pub post: Option<String>,
+    /// Each command is killed after this many seconds.
+    #[serde(default = "HookConfig::default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl HookConfig {
+    fn default_timeout_secs() -> u64 {
+        30
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+        glob::Pattern::new(&self.path).is_ok_and(|pattern| pattern.matches_with(path, options))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct LogConfig {
+    /// off, error, warn, info, debug or trace.
+    pub level: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: String::from("info"),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("read config {:?} err: {}", path, e))?;
+        let cfg: ServerConfig = toml::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("parse config {:?} err: {}", path, e))?;
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for addr in self.listen.iter() {
+            SocketAddr::from_str(addr)
+                .map_err(|e| anyhow::anyhow!("listen '{}' is invalid: {}", addr, e))?;
+        }
+        for root in self.roots.iter() {
+            if !Path::new(root).is_absolute() {
+                anyhow::bail!("root '{}' must be an absolute path", root);
+            }
+        }
+        let mut names = std::collections::HashSet::new();
+        for token in self.tokens.iter() {
+            if token.token.is_empty() {
+                anyhow::bail!("token '{}' has an empty secret", token.name);
+            }
+            if !names.insert(token.name.as_str()) {
+                anyhow::bail!("token name '{}' is duplicated", token.name);
+            }
+            for acl in token.acl.iter() {
+                if !Path::new(&acl.path).is_absolute() {
+                    anyhow::bail!(
+                        "acl path '{}' of token '{}' must be absolute",
+                        acl.path,
+                        token.name
+                    );
+                }
+            }
+        }
+        if let Some(dir) = &self.sessions.dir {
+            if !Path::new(dir).is_absolute() {
+                anyhow::bail!("sessions.dir '{}' must be an absolute path", dir);
+            }
+        }
+        if self.rate.requests_per_sec.is_some_and(|rate| rate <= 0.0)
+            || self.rate.burst.is_some_and(|burst| burst < 1.0)
+        {
+            anyhow::bail!("rate.requests_per_sec must be positive and rate.burst at least 1");
+        }
+        if self.rate.global_bytes_per_sec == Some(0)
+            || self.rate.connection_bytes_per_sec == Some(0)
+        {
+            anyhow::bail!(
+                "rate.global_bytes_per_sec and rate.connection_bytes_per_sec must be positive"
+            );
+        }
+        if let Some(path) = &self.audit.path {
+            if !Path::new(path).is_absolute() {
+                anyhow::bail!("audit.path '{}' must be an absolute path", path);
+            }
+        }
+        if self.audit.keep == 0 {
+            anyhow::bail!("audit.keep must be at least 1");
+        }
+        if self.backup.keep == Some(0) {
+            anyhow::bail!("backup.keep must be at least 1");
+        }
+        for hook in self.hooks.iter() {
+            if !Path::new(&hook.path).is_absolute() {
+                anyhow::bail!("hook path '{}' must be absolute", hook.path);
+            }
+            glob::Pattern::new(&hook.path)
+                .map_err(|e| anyhow::anyhow!("hook path '{}' is invalid: {}", hook.path, e))?;
+            if hook.validate.is_none() && hook.post.is_none() {
+                anyhow::bail!("hook '{}' needs a validate or post command", hook.path);
+            }
+            if hook.timeout_secs == 0 {
+                anyhow::bail!("timeout_secs of hook '{}' must be at least 1", hook.path);
+            }
+        }
+        self.log_level()?;
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> anyhow::Result<LevelFilter> {
+        LevelFilter::from_str(&self.log.level)
+            .map_err(|_| anyhow::anyhow!("log level '{}' is invalid", self.log.level))
+    }
+
+    /// Whether `path` lies below one of the roots. `..` components are never allowed.
+    pub fn in_roots(&self, path: &str) -> bool {
+        let path = Path::new(path);
+        if path.components().any(|c| c == Component::ParentDir) {
+            return false;
+        }
+        self.roots.is_empty() || self.roots.iter().any(|root| path.starts_with(root))
+    }
+}
+
+/// The live config. Handlers take a snapshot per request, so a reload never
+/// changes the settings of a transfer that is already running.
+pub struct SharedConfig {
+    path: Option<PathBuf>,
+    current: RwLock<Arc<ServerConfig>>,
+}
+
+impl SharedConfig {
+    pub fn new(path: Option<PathBuf>, cfg: ServerConfig) -> Self {
+        SharedConfig {
+            path,
+            current: RwLock::new(Arc::new(cfg)),
+        }
+    }
+
+    pub fn current(&self) -> Arc<ServerConfig> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Re-read the config file. The old config stays active if the new one is invalid.
+    pub fn reload(&self) -> anyhow::Result<Arc<ServerConfig>> {
+        let Some(path) = &self.path else {
+            anyhow::bail!("server is running without a config file");
+        };
+        let cfg = Arc::new(ServerConfig::load(path)?);
+        *self.current.write().unwrap() = cfg.clone();
+        Ok(cfg)
+    }
+}
@@ -1,2 +1,3 @@
 pub mod apis;
+pub mod config;
 pub mod util;
@@ -0,0 +1,92 @@
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Content codings the server decodes, in order of preference. Sent in the
+/// `Accept-Encoding` response header so clients can negotiate.
+pub const SUPPORTED: &str = "zstd, gzip";
+
+/// Extensions of formats that do not shrink any further.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "gz", "tgz", "zst", "xz", "bz2", "lz4", "br", "zip", "jar", "7z", "rar", "jpg", "jpeg", "png",
+    "gif", "webp", "avif", "mp3", "mp4", "m4a", "mkv", "webm", "mov", "ogg", "woff2",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd",
+            Self::Gzip => "gzip",
+        }
+    }
+
+    /// The preferred coding of an `Accept-Encoding` value.
+    pub fn negotiate(accept: &str) -> Option<Self> {
+        let offered: Vec<Encoding> = accept
+            .split(',')
+            .filter_map(|coding| coding.split(';').next())
+            .filter_map(|coding| Encoding::from_str(coding.trim()).ok())
+            .collect();
+        [Self::Zstd, Self::Gzip]
+            .into_iter()
+            .find(|encoding| offered.contains(encoding))
+    }
+
+    pub fn encode(&self, content: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Zstd => zstd::stream::encode_all(content, 3),
+            Self::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(content)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    /// Decode at most `limit` bytes; more is a `FileTooLarge` error, so a small
+    /// body cannot expand past the upload limit.
+    pub fn decode(&self, content: &[u8], limit: u64) -> io::Result<Vec<u8>> {
+        let reader: Box<dyn Read + '_> = match self {
+            Self::Zstd => Box::new(zstd::stream::read::Decoder::new(content)?),
+            Self::Gzip => Box::new(flate2::read::GzDecoder::new(content)),
+        };
+        let mut decoded = Vec::new();
+        reader
+            .take(limit.saturating_add(1))
+            .read_to_end(&mut decoded)?;
+        if decoded.len() as u64 > limit {
+            return Err(io::Error::new(
+                io::ErrorKind::FileTooLarge,
+                format!("decoded content exceeds {} bytes", limit),
+            ));
+        }
+        Ok(decoded)
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "zstd" => Ok(Self::Zstd),
+            "gzip" | "x-gzip" => Ok(Self::Gzip),
+            other => Err(format!("unsupported content encoding '{}'", other)),
+        }
+    }
+}
+
+/// Whether `path` names an already compressed format, judged by its extension.
+pub fn is_compressed_format<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| COMPRESSED_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
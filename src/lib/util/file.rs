@@ -1,14 +1,61 @@
-use std::{fs, path::Path};
+use std::{fs, io, path::Path};
 
 pub fn create_and_write<P: AsRef<Path>, C: AsRef<[u8]>>(
     path: P,
     contents: C,
 ) -> anyhow::Result<()> {
-    let dir = path.as_ref().parent();
-    if dir.is_some() && !dir.unwrap().exists() {
-        fs::create_dir_all(dir.unwrap())?;
+    if let Some(dir) = path.as_ref().parent() {
+        if !dir.exists() {
+            fs::create_dir_all(dir)?;
+        }
     }
 
     fs::write(path, contents)?;
     Ok(())
 }
+
+/// Bytes available to unprivileged writers on the filesystem holding `path`.
+/// A path that does not exist yet is looked up through its nearest existing parent.
+#[cfg(unix)]
+pub fn available_space<P: AsRef<Path>>(path: P) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = path
+        .as_ref()
+        .ancestors()
+        .find(|dir| dir.exists())
+        .unwrap_or(Path::new("/"));
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    // SAFETY: statvfs is plain integers, for which all zero bytes are valid.
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: c_path is a NUL terminated string and stat a valid statvfs, both
+    // outliving the call, which keeps neither pointer.
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn available_space<P: AsRef<Path>>(_path: P) -> io::Result<u64> {
+    Ok(u64::MAX)
+}
+
+/// Device of the filesystem holding `path`, looked up like `available_space`.
+/// Paths with the same id share their free space.
+#[cfg(unix)]
+pub fn filesystem_id<P: AsRef<Path>>(path: P) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    path.as_ref()
+        .ancestors()
+        .find_map(|dir| std::fs::metadata(dir).ok())
+        .map(|meta| meta.dev())
+}
+
+#[cfg(not(unix))]
+pub fn filesystem_id<P: AsRef<Path>>(_path: P) -> Option<u64> {
+    None
+}
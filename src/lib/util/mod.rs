@@ -1,2 +1,4 @@
+pub mod compress;
 pub mod file;
 pub mod schema;
+pub mod throttle;
@@ -3,17 +3,26 @@ use std::{fmt::Display, str::FromStr};
 #[derive(Debug, Default)]
 pub struct UploadForm {
     pub action: Action,
-    pub content: String,
+    pub content: Vec<u8>,
     pub target_file_path: String,
+    /// Permission bits applied to the written file.
+    pub mode: Option<u32>,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub enum Action {
     #[default]
     Safe,
     Force,
 }
 
+/// Parse octal permission bits such as `0644`.
+pub fn parse_mode(s: &str) -> Option<u32> {
+    u32::from_str_radix(s.trim(), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+}
+
 #[derive(Debug)]
 pub struct NotActionError;
 
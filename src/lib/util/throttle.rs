@@ -0,0 +1,190 @@
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A token bucket refilled at `rate` tokens per second, holding at most `burst`.
+#[derive(Debug)]
+pub struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    pub fn new(burst: f64) -> Self {
+        Bucket {
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, rate: f64, burst: f64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.last = now;
+    }
+
+    /// Take `n` tokens, going into debt when there are not enough. Returns
+    /// how long the caller should wait before using them.
+    pub fn take(&mut self, n: f64, rate: f64, burst: f64) -> Duration {
+        self.refill(rate, burst);
+        self.tokens -= n;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+
+    /// Take `n` tokens only when they are there, or tell how long until they are.
+    pub fn try_take(&mut self, n: f64, rate: f64, burst: f64) -> Result<(), Duration> {
+        self.refill(rate, burst);
+        if self.tokens >= n {
+            self.tokens -= n;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((n - self.tokens) / rate))
+        }
+    }
+
+    /// Whether the bucket refilled completely, i.e. it was not used for a while.
+    pub fn is_full(&mut self, rate: f64, burst: f64) -> bool {
+        self.refill(rate, burst);
+        self.tokens >= burst
+    }
+}
+
+/// Caps a byte stream, or several sharing one throttle, at `rate` bytes per second.
+#[derive(Debug)]
+pub struct Throttle {
+    rate: u64,
+    bucket: Mutex<Bucket>,
+}
+
+impl Throttle {
+    pub fn new(rate: u64) -> Self {
+        Throttle {
+            rate,
+            bucket: Mutex::new(Bucket::new(rate as f64)),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// How long to pause after passing `n` bytes on.
+    pub fn delay(&self, n: usize) -> Duration {
+        let rate = self.rate as f64;
+        self.bucket.lock().unwrap().take(n as f64, rate, rate)
+    }
+}
+
+/// Bytes a throttled stream passes on at once, so one large chunk is spread out too.
+pub const THROTTLE_CHUNK: usize = 16 * 1024;
+
+/// A reader that sleeps to stay under its throttle.
+pub struct ThrottledRead<R> {
+    inner: R,
+    throttle: Arc<Throttle>,
+}
+
+impl<R: Read> ThrottledRead<R> {
+    pub fn new(inner: R, throttle: Arc<Throttle>) -> Self {
+        ThrottledRead { inner, throttle }
+    }
+}
+
+impl<R: Read> Read for ThrottledRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(THROTTLE_CHUNK);
+        let n = self.inner.read(&mut buf[..len])?;
+        std::thread::sleep(self.throttle.delay(n));
+        Ok(n)
+    }
+}
+
+/// Parse a byte rate such as `500000`, `512K` or `10M` (powers of 1024).
+pub fn parse_rate(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let multiplier: u64 = match unit.to_ascii_uppercase().trim_end_matches("/S") {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        other => return Err(format!("unknown unit '{}' in '{}'", other, s)),
+    };
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| format!("'{}' is not a byte rate like 512K or 10M", s))?;
+    match value.checked_mul(multiplier) {
+        Some(0) => Err("rate must be greater than 0".to_string()),
+        Some(rate) => Ok(rate),
+        None => Err(format!("'{}' is too large", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A bucket holding `tokens`, last refilled `ago` before now.
+    fn filled(tokens: f64, ago: Duration) -> Bucket {
+        Bucket {
+            tokens,
+            last: Instant::now() - ago,
+        }
+    }
+
+    fn close(a: Duration, b: Duration) -> bool {
+        a.abs_diff(b) < Duration::from_millis(50)
+    }
+
+    #[test]
+    fn try_take_allows_a_burst_then_waits_for_one_token() {
+        let mut bucket = Bucket::new(3.0);
+        for _ in 0..3 {
+            assert!(bucket.try_take(1.0, 2.0, 3.0).is_ok());
+        }
+        let wait = bucket.try_take(1.0, 2.0, 3.0).unwrap_err();
+        assert!(close(wait, Duration::from_millis(500)), "{:?}", wait);
+    }
+
+    #[test]
+    fn take_goes_into_debt() {
+        let mut bucket = filled(100.0, Duration::ZERO);
+        assert_eq!(bucket.take(50.0, 100.0, 100.0), Duration::ZERO);
+        let wait = bucket.take(250.0, 100.0, 100.0);
+        assert!(close(wait, Duration::from_secs(2)), "{:?}", wait);
+        // The debt is paid before anything is left.
+        let wait = bucket.take(100.0, 100.0, 100.0);
+        assert!(close(wait, Duration::from_secs(3)), "{:?}", wait);
+    }
+
+    #[test]
+    fn refill_stops_at_burst() {
+        let mut bucket = filled(0.0, Duration::from_secs(100));
+        assert!(bucket.is_full(1.0, 5.0));
+        assert!(bucket.try_take(5.0, 1.0, 5.0).is_ok());
+        assert!(bucket.try_take(1.0, 1.0, 5.0).is_err());
+
+        let mut bucket = filled(0.0, Duration::from_secs(2));
+        assert!(!bucket.is_full(1.0, 5.0));
+    }
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("500000"), Ok(500000));
+        assert_eq!(parse_rate("512K"), Ok(512 << 10));
+        assert_eq!(parse_rate("10mib/s"), Ok(10 << 20));
+        assert_eq!(parse_rate(" 1G "), Ok(1 << 30));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("10X").is_err());
+        assert!(parse_rate("M").is_err());
+        assert!(parse_rate("99999999999G").is_err());
+    }
+}
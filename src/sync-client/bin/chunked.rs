@@ -0,0 +1,161 @@
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Arc;
+
+use lib::apis::response::ApiResponse;
+use lib::apis::urls;
+use log::debug;
+use serde_json::json;
+
+use crate::config::{Config, Sent};
+use crate::mapping::FileMapping;
+use crate::output::ClientError;
+use crate::parse_response;
+use crate::progress::FileBar;
+
+/// Upload a large file through a resumable session: only the ranges the
+/// server is missing are sent, so a failed attempt, or a rerun of the
+/// command, continues where the last one stopped.
+pub fn upload(
+    mapping: &FileMapping,
+    action: &str,
+    size: u64,
+    cfg: &Config,
+) -> Result<ApiResponse, ClientError> {
+    let hash = file_md5(&mapping.local)?;
+    let bar = cfg.file_bar(&mapping.local, Some(size));
+    let what = format!("push {} => {} in chunks", mapping.local, mapping.remote);
+    cfg.retry.run(&what, || {
+        upload_once(mapping, action, size, &hash, cfg, bar.clone())
+    })
+}
+
+fn upload_once(
+    mapping: &FileMapping,
+    action: &str,
+    size: u64,
+    hash: &str,
+    cfg: &Config,
+    bar: Option<Arc<FileBar>>,
+) -> Result<ApiResponse, ClientError> {
+    let url = urls::SESSIONS_URL_V1!(cfg.protocol.data(), cfg.addr);
+    let session = cfg
+        .make_request(url)
+        .json(&json!({
+            "target_file_path": mapping.remote,
+            "action": action,
+            "mode": mapping.mode,
+            "size": size,
+            "hash": hash,
+        }))
+        .send()
+        .map_err(ClientError::from)
+        .and_then(parse_response)?;
+    let Some(id) = session.id.clone() else {
+        return Err(ClientError::Local(
+            "server answered no session id".to_string(),
+        ));
+    };
+    let url = urls::SESSION_URL_V1!(cfg.protocol.data(), cfg.addr, id);
+
+    let missing = missing(&session.ranges, size);
+    if let Some(bar) = &bar {
+        // Start from what the server already has.
+        let received: u64 = session.ranges.iter().map(|[start, end]| end - start).sum();
+        bar.reset(received);
+    }
+    let mut file = fs::File::open(&mapping.local).map_err(|e| ClientError::Local(e.to_string()))?;
+    for [start, end] in missing {
+        let mut offset = start;
+        while offset < end {
+            let len = (end - offset).min(cfg.chunk_size);
+            let mut chunk = vec![0; len as usize];
+            file.seek(SeekFrom::Start(offset))
+                .and_then(|_| file.read_exact(&mut chunk))
+                .map_err(|e| ClientError::Local(e.to_string()))?;
+            debug!("{} => session {}: {}+{}", mapping.local, id, offset, len);
+            let sent = Sent::default();
+            let request = cfg.make_put(format!("{}?offset={}", url, offset)).body(
+                reqwest::blocking::Body::sized(cfg.body_reader(chunk, bar.clone(), &sent), len),
+            );
+            cfg.send_upload(request, &sent).and_then(parse_response)?;
+            offset += len;
+        }
+    }
+
+    cfg.make_request(format!("{}/commit", url))
+        .send()
+        .map_err(ClientError::from)
+        .and_then(parse_response)
+}
+
+/// Ranges of `[0, size)` not covered by the `received` ranges, in order.
+fn missing(received: &[[u64; 2]], size: u64) -> Vec<[u64; 2]> {
+    let mut received: Vec<[u64; 2]> = received
+        .iter()
+        .copied()
+        .filter(|[start, end]| start < end)
+        .collect();
+    received.sort_unstable();
+    let mut gaps = Vec::new();
+    let mut next = 0;
+    for [start, end] in received {
+        let start = start.min(size);
+        if start > next {
+            gaps.push([next, start]);
+        }
+        next = next.max(end);
+    }
+    if next < size {
+        gaps.push([next, size]);
+    }
+    gaps
+}
+
+fn file_md5(local_file: &str) -> Result<String, ClientError> {
+    let mut file = fs::File::open(local_file).map_err(|e| ClientError::Local(e.to_string()))?;
+    let mut context = md5::Context::new();
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        let n = file
+            .read(&mut buf)
+            .map_err(|e| ClientError::Local(e.to_string()))?;
+        if n == 0 {
+            break;
+        }
+        context.consume(&buf[..n]);
+    }
+    Ok(format!("{:x}", context.compute()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::missing;
+
+    #[test]
+    fn nothing_received() {
+        assert_eq!(missing(&[], 10), vec![[0, 10]]);
+        assert_eq!(missing(&[], 0), Vec::<[u64; 2]>::new());
+    }
+
+    #[test]
+    fn gaps_between_ranges() {
+        assert_eq!(
+            missing(&[[2, 4], [6, 8]], 10),
+            vec![[0, 2], [4, 6], [8, 10]]
+        );
+        assert_eq!(missing(&[[0, 10]], 10), Vec::<[u64; 2]>::new());
+    }
+
+    #[test]
+    fn overlapping_and_unsorted_ranges() {
+        assert_eq!(missing(&[[5, 8], [0, 3], [2, 6]], 10), vec![[8, 10]]);
+        assert_eq!(missing(&[[3, 9], [4, 5]], 10), vec![[0, 3], [9, 10]]);
+    }
+
+    #[test]
+    fn empty_and_out_of_bounds_ranges() {
+        assert_eq!(missing(&[[4, 4], [0, 2]], 6), vec![[2, 6]]);
+        assert_eq!(missing(&[[0, 4], [8, 20]], 6), vec![[4, 6]]);
+    }
+}
@@ -0,0 +1,590 @@
+use std::collections::HashMap;
+use std::io::{self, Cursor, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use std::{env, fs, path, thread};
+
+use clap::ValueEnum;
+use lib::apis::capabilities::{feature, Capabilities, PROTOCOL_VERSION};
+use lib::apis::urls;
+use lib::util::compress::{is_compressed_format, Encoding};
+use lib::util::throttle::{Throttle, ThrottledRead};
+use log::{debug, warn};
+use reqwest::blocking::{RequestBuilder, Response};
+use reqwest::{header, StatusCode};
+use serde::Deserialize;
+
+use crate::output::{usage_bail, ClientError, UsageError};
+use crate::progress::{FileBar, Progress, ProgressRead};
+use crate::retry::RetryPolicy;
+use crate::Args;
+
+pub enum ReqProtocol {
+    Http(String),
+    Https(String),
+}
+
+impl ReqProtocol {
+    fn new(protocol: impl Into<String>) -> Self {
+        let protocol: String = protocol.into();
+        let protocol: &str = &protocol.to_lowercase();
+        match protocol {
+            "http" => Self::Http(protocol.to_string()),
+            "https" => Self::Https(protocol.to_string()),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn data(&self) -> String {
+        match self {
+            Self::Http(data) => data.clone(),
+            Self::Https(data) => data.clone(),
+        }
+    }
+
+    /// `timeout` bounds the wait for an answer, and every wait for more of its body.
+    fn new_client(
+        &self,
+        remote: &Remote,
+        compress: Compression,
+        connect_timeout: Duration,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<reqwest::blocking::Client> {
+        let builder = reqwest::blocking::Client::builder()
+            .connect_timeout(connect_timeout)
+            .timeout(timeout)
+            // Notice a peer that went away while waiting for its answer.
+            .tcp_keepalive(Duration::from_secs(15));
+        // Without gzip and zstd reqwest neither asks for nor decodes compressed responses.
+        let builder = match compress {
+            Compression::Off => builder.no_gzip().no_zstd(),
+            _ => builder,
+        };
+        match self {
+            ReqProtocol::Http(_) => builder.build().map_err(anyhow::Error::from),
+            ReqProtocol::Https(_) => {
+                let mut builder =
+                    builder.danger_accept_invalid_certs(remote.insecure.unwrap_or(false));
+                if let Some(ca_cert) = &remote.ca_cert {
+                    let pem = fs::read(ca_cert)
+                        .map_err(|e| anyhow::anyhow!("read ca_cert {} err: {}", ca_cert, e))?;
+                    builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+                }
+                builder.build().map_err(anyhow::Error::from)
+            }
+        }
+    }
+}
+
+/// Compression of uploaded files and downloaded responses.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Compress files that shrink, if the server accepts it.
+    #[default]
+    Auto,
+    /// Compress every file.
+    On,
+    /// Send and receive raw bytes.
+    Off,
+}
+
+/// Files smaller than this are not worth compressing.
+const MIN_COMPRESS_BYTES: usize = 1024;
+
+/// A named server in the client config file.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Remote {
+    pub addr: Option<String>,
+    /// Value of the `Host` request header.
+    pub host: Option<String>,
+    /// Talk https to the remote.
+    pub tls: Option<bool>,
+    /// Accept invalid certificates, implies `tls`.
+    pub insecure: Option<bool>,
+    /// PEM file of an extra root certificate.
+    pub ca_cert: Option<String>,
+    /// Sent as `Authorization: Bearer <token>`.
+    pub token: Option<String>,
+    /// Default `--action` of pushes to this remote.
+    pub action: Option<String>,
+}
+
+/// `~/.config/sync-file/config.toml`
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Remote used when neither `--remote` nor `--addr` is given.
+    pub default_remote: Option<String>,
+    #[serde(default)]
+    pub remotes: HashMap<String, Remote>,
+}
+
+impl ConfigFile {
+    pub fn default_path() -> Option<path::PathBuf> {
+        let base = env::var_os("XDG_CONFIG_HOME")
+            .map(path::PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| path::Path::new(&home).join(".config")))?;
+        Some(base.join("sync-file").join("config.toml"))
+    }
+
+    /// Load `explicit`, or the default path when it exists.
+    pub fn load(explicit: Option<&str>) -> anyhow::Result<Self> {
+        let file = match explicit {
+            Some(file) => path::PathBuf::from(file),
+            None => match Self::default_path() {
+                Some(file) if file.exists() => file,
+                _ => return Ok(ConfigFile::default()),
+            },
+        };
+        let content = fs::read_to_string(&file)
+            .map_err(|e| UsageError::from_err(format!("read config {:?} err: {}", file, e)))?;
+        toml::from_str(&content)
+            .map_err(|e| UsageError::from_err(format!("parse config {:?} err: {}", file, e)))
+    }
+
+    fn remote(&self, name: Option<&str>) -> anyhow::Result<Remote> {
+        let Some(name) = name.or(self.default_remote.as_deref()) else {
+            return Ok(Remote::default());
+        };
+        self.remotes
+            .get(name)
+            .cloned()
+            .ok_or_else(|| UsageError::from_err(format!("remote '{}' is not configured", name)))
+    }
+}
+
+pub struct Config {
+    pub addr: String,
+    pub header_host: Option<String>,
+    pub protocol: ReqProtocol,
+    /// Shared by every request so connections are pooled across files.
+    pub client: reqwest::blocking::Client,
+    /// Sends file contents. Without the idle timeout of `client`, which
+    /// would also cap how long sending a file may take; `send_upload`
+    /// watches for stalls instead.
+    upload_client: reqwest::blocking::Client,
+    /// `--idle-timeout`.
+    idle_timeout: Duration,
+    /// `--timeout` of a whole upload or download.
+    timeout: Option<Duration>,
+    pub jobs: usize,
+    /// Only report what would be transferred or deleted.
+    pub dry_run: bool,
+    pub token: Option<String>,
+    /// Push action when neither the mapping nor `--action` sets one.
+    pub action: String,
+    pub compress: Compression,
+    /// Files larger than this are uploaded in chunks of this size.
+    pub chunk_size: u64,
+    pub retry: RetryPolicy,
+    /// Progress bars, when stdout and stderr are terminals.
+    pub progress: Option<Arc<Progress>>,
+    /// `--bwlimit`, shared by every transfer.
+    bwlimit: Option<Arc<Throttle>>,
+    /// What the server speaks, asked on first use until it answers.
+    capabilities: OnceLock<Capabilities>,
+    /// Assumed while the server cannot be asked: that it is current.
+    assumed: Capabilities,
+}
+
+impl Config {
+    pub fn make_request(&self, url: impl AsRef<str>) -> RequestBuilder {
+        self.decorate(self.client.post(self.route(url.as_ref())))
+    }
+
+    pub fn make_get(&self, url: impl AsRef<str>) -> RequestBuilder {
+        self.decorate(self.client.get(self.route(url.as_ref())))
+    }
+
+    /// A POST carrying file contents.
+    pub fn make_upload(&self, url: impl AsRef<str>) -> RequestBuilder {
+        self.decorate_upload(self.upload_client.post(self.route(url.as_ref())))
+    }
+
+    /// A PUT carrying file contents.
+    pub fn make_put(&self, url: impl AsRef<str>) -> RequestBuilder {
+        self.decorate_upload(self.upload_client.put(self.route(url.as_ref())))
+    }
+
+    /// A server from before `/v1` serves the same api without the prefix.
+    fn route(&self, url: &str) -> String {
+        if self.capabilities().versions.is_empty() {
+            unversioned(url)
+        } else {
+            url.to_string()
+        }
+    }
+
+    pub fn capabilities(&self) -> &Capabilities {
+        if let Some(capabilities) = self.capabilities.get() {
+            return capabilities;
+        }
+        // Not kept, so the next request asks again.
+        let Some(capabilities) = self.negotiate() else {
+            return &self.assumed;
+        };
+        self.capabilities.get_or_init(|| {
+            debug!("server capabilities: {:?}", capabilities);
+            if !capabilities.versions.is_empty()
+                && !capabilities.versions.contains(&PROTOCOL_VERSION)
+            {
+                warn!(
+                    "server speaks protocol versions {:?} but this client speaks {}, upgrade it",
+                    capabilities.versions, PROTOCOL_VERSION
+                );
+            }
+            capabilities
+        })
+    }
+
+    /// `None` when the server could not be asked.
+    fn negotiate(&self) -> Option<Capabilities> {
+        let url = urls::CAPABILITIES_URL!(self.protocol.data(), self.addr);
+        let result = match self.decorate(self.client.get(url)).send() {
+            Ok(resp) if resp.status() == StatusCode::NOT_FOUND => {
+                return Some(self.legacy_capabilities())
+            }
+            Ok(resp) => crate::parse_response(resp),
+            // The requests that follow report the server being unreachable.
+            Err(err) => Err(err.into()),
+        };
+        match result {
+            Ok(body) => Some(
+                body.capabilities
+                    .unwrap_or_else(|| self.legacy_capabilities()),
+            ),
+            Err(err) => {
+                debug!(
+                    "ask capabilities err: {}, assume the server is current",
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    /// Fail with a usage error unless the server supports `feature`, which `what` needs.
+    pub fn require(&self, feature: &str, what: &str) -> anyhow::Result<()> {
+        if !self.capabilities().supports(feature) {
+            usage_bail!(
+                "{} needs a server supporting '{}', this one does not, upgrade it",
+                what,
+                feature
+            );
+        }
+        Ok(())
+    }
+
+    /// Only unversioned routes and no optional features, except the
+    /// compression named in the `Accept-Encoding` header of `/ping`.
+    fn legacy_capabilities(&self) -> Capabilities {
+        let url = unversioned(&urls::PING_URL_V1!(self.protocol.data(), self.addr));
+        let encodings: Vec<String> = self
+            .decorate(self.client.get(url))
+            .send()
+            .ok()
+            .and_then(|resp| {
+                resp.headers()
+                    .get(header::ACCEPT_ENCODING)
+                    .and_then(|v| v.to_str().ok())
+                    .map(|v| {
+                        v.split(',')
+                            .map(|coding| coding.trim().to_string())
+                            .collect()
+                    })
+            })
+            .unwrap_or_default();
+        Capabilities {
+            features: if encodings.is_empty() {
+                Vec::new()
+            } else {
+                vec![feature::COMPRESSION.to_string()]
+            },
+            encodings,
+            ..Default::default()
+        }
+    }
+
+    fn decorate_upload(&self, request: RequestBuilder) -> RequestBuilder {
+        match self.timeout {
+            Some(timeout) => self.decorate(request).timeout(timeout),
+            None => self.decorate(request),
+        }
+    }
+
+    /// `--compress on` needs a server that decodes uploads; an older one would
+    /// write the compressed bytes as the file. Checked before anything is sent.
+    pub fn check_compression(&self) -> anyhow::Result<()> {
+        if self.compress != Compression::On || self.dry_run {
+            return Ok(());
+        }
+        let capabilities = self.capabilities();
+        if !capabilities.supports(feature::COMPRESSION) {
+            usage_bail!(
+                "--compress on: the server does not support compressed uploads, use auto or off"
+            );
+        }
+        if self.server_encoding().is_none() {
+            usage_bail!(
+                "--compress on: the server accepts {:?}, none of which this client can send",
+                capabilities.encodings
+            );
+        }
+        Ok(())
+    }
+
+    /// Coding for uploading `content` of `local_file`, if compressing is worth it.
+    pub fn upload_encoding(&self, local_file: &str, content: &[u8]) -> Option<Encoding> {
+        match self.compress {
+            Compression::Off => None,
+            Compression::On => self.server_encoding(),
+            Compression::Auto
+                if content.len() < MIN_COMPRESS_BYTES || is_compressed_format(local_file) =>
+            {
+                None
+            }
+            Compression::Auto => self.server_encoding(),
+        }
+    }
+
+    /// The best coding the server decodes, none if it does not decode uploads.
+    fn server_encoding(&self) -> Option<Encoding> {
+        let capabilities = self.capabilities();
+        if !capabilities.supports(feature::COMPRESSION) {
+            return None;
+        }
+        Encoding::negotiate(&capabilities.encodings.join(","))
+    }
+
+    /// A progress bar for transferring `name`, unless bars are disabled.
+    pub fn file_bar(&self, name: &str, len: Option<u64>) -> Option<Arc<FileBar>> {
+        self.progress
+            .as_ref()
+            .map(|progress| progress.file(name, len))
+    }
+
+    /// The bar of `name` kept in `bar` across the attempts of a transfer:
+    /// made by the first attempt, moved back to the start by every later one.
+    pub fn attempt_bar(
+        &self,
+        bar: &mut Option<Arc<FileBar>>,
+        name: &str,
+        len: Option<u64>,
+    ) -> Option<Arc<FileBar>> {
+        match bar {
+            Some(bar) => {
+                bar.reset(0);
+                Some(bar.clone())
+            }
+            None => {
+                *bar = self.file_bar(name, len);
+                bar.clone()
+            }
+        }
+    }
+
+    /// A request body that keeps to `--bwlimit`, advances `bar`, and counts
+    /// what it handed out in `sent`.
+    pub fn body_reader(
+        &self,
+        content: Vec<u8>,
+        bar: Option<Arc<FileBar>>,
+        sent: &Sent,
+    ) -> Box<dyn Read + Send> {
+        let reader: Box<dyn Read + Send> = match &self.bwlimit {
+            Some(throttle) => Box::new(ThrottledRead::new(Cursor::new(content), throttle.clone())),
+            None => Box::new(Cursor::new(content)),
+        };
+        let reader = Box::new(CountRead {
+            inner: reader,
+            sent: sent.clone(),
+        });
+        match bar {
+            Some(bar) => Box::new(ProgressRead::new(reader, bar)),
+            None => reader,
+        }
+    }
+
+    /// Send an upload whose body counts into `sent`. It fails once
+    /// `--idle-timeout` passes without more of the body being sent, or
+    /// without an answer after all of it was. The request runs on a thread of
+    /// its own, left behind when it stalls since a blocking request cannot be
+    /// cancelled.
+    pub fn send_upload(
+        &self,
+        request: RequestBuilder,
+        sent: &Sent,
+    ) -> Result<Response, ClientError> {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = tx.send(request.send());
+        });
+        let tick = self.idle_timeout.min(Duration::from_secs(1));
+        let mut last = (sent.get(), Instant::now());
+        loop {
+            match rx.recv_timeout(tick) {
+                Ok(result) => return result.map_err(ClientError::from),
+                Err(RecvTimeoutError::Timeout) => {
+                    let now = sent.get();
+                    if now != last.0 {
+                        last = (now, Instant::now());
+                    } else if last.1.elapsed() >= self.idle_timeout {
+                        return Err(ClientError::Connection(format!(
+                            "timed out: no progress for {}s after sending {} bytes",
+                            self.idle_timeout.as_secs(),
+                            now
+                        )));
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(ClientError::Connection(
+                        "upload thread ended without an answer".to_string(),
+                    ))
+                }
+            }
+        }
+    }
+
+    /// A response body that keeps to `--bwlimit`, advances `bar`, and fails
+    /// once the request `started` longer than `--timeout` ago.
+    pub fn response_reader(
+        &self,
+        resp: Response,
+        started: Instant,
+        bar: Option<Arc<FileBar>>,
+    ) -> Box<dyn Read> {
+        let reader: Box<dyn Read> = match &self.bwlimit {
+            Some(throttle) => Box::new(ThrottledRead::new(resp, throttle.clone())),
+            None => Box::new(resp),
+        };
+        let reader: Box<dyn Read> = match bar {
+            Some(bar) => Box::new(ProgressRead::new(reader, bar)),
+            None => reader,
+        };
+        match self.timeout {
+            Some(timeout) => Box::new(DeadlineRead {
+                inner: reader,
+                deadline: started + timeout,
+            }),
+            None => reader,
+        }
+    }
+
+    fn decorate(&self, request: RequestBuilder) -> RequestBuilder {
+        let request = match &self.header_host {
+            Some(host) => request.header("Host", host),
+            None => request,
+        };
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+}
+
+fn unversioned(url: &str) -> String {
+    url.replacen("/v1/", "/", 1)
+}
+
+/// Bytes of a request body read so far, to tell a stalled upload from a slow one.
+#[derive(Debug, Clone, Default)]
+pub struct Sent(Arc<AtomicU64>);
+
+impl Sent {
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+struct CountRead<R> {
+    inner: R,
+    sent: Sent,
+}
+
+impl<R: Read> Read for CountRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.sent.0.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+/// Fails reads once `deadline` has passed.
+struct DeadlineRead<R> {
+    inner: R,
+    deadline: Instant,
+}
+
+impl<R: Read> Read for DeadlineRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if Instant::now() > self.deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "transfer exceeded --timeout",
+            ));
+        }
+        self.inner.read(buf)
+    }
+}
+
+impl Config {
+    /// Command line flags win over the selected remote of the config file.
+    pub fn new(args: &Args, progress: Option<Arc<Progress>>) -> anyhow::Result<Self> {
+        let file = ConfigFile::load(args.config.as_deref())?;
+        let remote = if args.remote.is_none() && args.addr.is_some() {
+            Remote::default()
+        } else {
+            file.remote(args.remote.as_deref())?
+        };
+
+        let Some(addr) = args.addr.clone().or(remote.addr.clone()) else {
+            return Err(UsageError::from_err("--addr or --remote is required"));
+        };
+        let remote = Remote {
+            insecure: Some(args.enable_insecure_ssl || remote.insecure.unwrap_or(false)),
+            ..remote
+        };
+        let https = remote.tls.unwrap_or(false) || remote.insecure.unwrap_or(false);
+        let protocol = ReqProtocol::new(if https { "https" } else { "http" });
+        let connect_timeout = Duration::from_secs(args.global.connect_timeout);
+        let idle_timeout = Duration::from_secs(args.global.idle_timeout);
+        let client = protocol.new_client(
+            &remote,
+            args.global.compress,
+            connect_timeout,
+            Some(idle_timeout),
+        )?;
+        let upload_client =
+            protocol.new_client(&remote, args.global.compress, connect_timeout, None)?;
+        Ok(Config {
+            addr,
+            header_host: args.host.clone().or(remote.host),
+            protocol,
+            client,
+            upload_client,
+            idle_timeout,
+            timeout: args.global.timeout.map(Duration::from_secs),
+            jobs: args.global.jobs,
+            dry_run: args.global.dry_run,
+            token: args.token.clone().or(remote.token),
+            action: remote.action.unwrap_or_else(|| String::from("safe")),
+            compress: args.global.compress,
+            chunk_size: args.global.chunk_size,
+            retry: RetryPolicy {
+                retries: args.global.retries,
+                delay: Duration::from_millis(args.global.retry_delay_ms),
+                max_delay: Duration::from_millis(args.global.retry_max_delay_ms),
+            },
+            progress,
+            bwlimit: args
+                .global
+                .bwlimit
+                .map(|rate| Arc::new(Throttle::new(rate))),
+            capabilities: OnceLock::new(),
+            assumed: Capabilities::current(),
+        })
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::fs;
+
+use clap::Args as ClapArgs;
+use lib::apis::capabilities::feature;
+use lib::apis::response::ApiError;
+use lib::apis::upload::content_md5;
+use lib::apis::urls;
+use log::{error, info};
+use serde::Serialize;
+use similar::TextDiff;
+
+use crate::config::Config;
+use crate::mapping::{self, Direction, FileMapping};
+use crate::output::{exit_code, usage_bail, ClientError, OutputFormat, UsageError};
+use crate::plan::{existing, stat_remote};
+use crate::{fetch_file, parse_response};
+
+#[derive(ClapArgs, Debug)]
+pub struct DiffArgs {
+    #[arg(
+        long,
+        conflicts_with = "manifest",
+        help = "[local_file1]:[remote_file1],[local_file2]:[remote_file2],... (\\, \\: \\\\ escape ',' ':' '\\')"
+    )]
+    file_mappings: Option<String>,
+
+    #[arg(long, help = "TOML manifest declaring [[mapping]] entries")]
+    manifest: Option<String>,
+
+    #[arg(
+        long,
+        help = "Diff against a safe mode backup of the remote file: latest or a %Y%m%d_%H%M%S version"
+    )]
+    backup: Option<String>,
+
+    #[arg(long, default_value_t = 3, help = "Lines of context in a unified diff")]
+    context: usize,
+}
+
+/// How one local file compares to its remote side.
+#[derive(Serialize, Debug)]
+struct DiffResult {
+    local_path: String,
+    remote_path: String,
+    identical: bool,
+    binary: bool,
+    local_bytes: u64,
+    local_hash: String,
+    remote_bytes: u64,
+    /// None when the remote file does not exist.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_hash: Option<String>,
+    /// The remote file does not exist, so the whole local file is added.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    remote_missing: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diff: Option<String>,
+}
+
+/// Remote path and md5 of the backup `version` of `remote_path`.
+fn resolve_backup(
+    remote_path: &str,
+    version: &str,
+    cfg: &Config,
+) -> Result<(String, Option<String>), ClientError> {
+    let mut m = HashMap::new();
+    m.insert("file_path", remote_path);
+
+    let url = urls::BACKUPS_URL_V1!(cfg.protocol.data(), cfg.addr);
+    let body = cfg
+        .make_request(url)
+        .json(&m)
+        .send()
+        .map_err(ClientError::from)
+        .and_then(parse_response)?;
+    // Newest first.
+    let found = body.files.into_iter().find(|backup| {
+        version == "latest"
+            || backup
+                .path
+                .as_deref()
+                .is_some_and(|path| path.ends_with(&format!(".{}", version)))
+    });
+    match found {
+        Some(backup) => Ok((backup.path.unwrap_or_default(), backup.hash)),
+        None => Err(ClientError::Api {
+            status: 404,
+            err: ApiError::not_found(format!("no backup version {}", version))
+                .with_path(remote_path),
+            retry_after: None,
+        }),
+    }
+}
+
+fn is_text(content: &[u8]) -> bool {
+    !content.contains(&0) && std::str::from_utf8(content).is_ok()
+}
+
+fn diff_one(
+    args: &DiffArgs,
+    mapping: &FileMapping,
+    cfg: &Config,
+) -> Result<DiffResult, ClientError> {
+    let local = fs::read(&mapping.local).map_err(|e| ClientError::Local(e.to_string()))?;
+    let local_hash = content_md5(&local);
+
+    let (remote_path, remote_hash, remote_exists) = match &args.backup {
+        Some(version) => {
+            let (path, hash) = resolve_backup(&mapping.remote, version, cfg)?;
+            (path, hash, true)
+        }
+        None => {
+            let stats = stat_remote(vec![mapping.remote.as_str()], cfg)?;
+            match stats.first().map(existing).transpose()?.flatten() {
+                Some(stat) => (mapping.remote.clone(), stat.hash.clone(), true),
+                None => (mapping.remote.clone(), None, stats.is_empty()),
+            }
+        }
+    };
+    let mut result = DiffResult {
+        local_path: mapping.local.clone(),
+        remote_path: remote_path.clone(),
+        identical: true,
+        binary: !is_text(&local),
+        local_bytes: local.len() as u64,
+        local_hash: local_hash.clone(),
+        remote_bytes: local.len() as u64,
+        remote_hash: Some(local_hash.clone()),
+        remote_missing: false,
+        diff: None,
+    };
+    // The hash is enough to tell identical files apart, skip the download.
+    if remote_hash.as_deref() == Some(local_hash.as_str()) {
+        return Ok(result);
+    }
+
+    // A missing remote file, or one removed since the stat, diffs as empty.
+    let remote = match remote_exists {
+        false => None,
+        true => match fetch_file(&remote_path, cfg) {
+            Ok((remote, _)) => Some(remote),
+            Err(ClientError::Api { status: 404, .. }) if args.backup.is_none() => None,
+            Err(err) => return Err(err),
+        },
+    };
+    result.identical = false;
+    result.remote_missing = remote.is_none();
+    let remote = remote.unwrap_or_default();
+    result.remote_bytes = remote.len() as u64;
+    result.remote_hash = (!result.remote_missing).then(|| content_md5(&remote));
+    result.binary = result.binary || !is_text(&remote);
+    if !result.binary {
+        let (old, new) = (
+            String::from_utf8_lossy(&remote),
+            String::from_utf8_lossy(&local),
+        );
+        let old_header = match result.remote_missing {
+            true => "/dev/null".to_string(),
+            false => format!("remote:{}", remote_path),
+        };
+        let diff = TextDiff::from_lines(old.as_ref(), new.as_ref())
+            .unified_diff()
+            .context_radius(args.context)
+            .header(&old_header, &mapping.local)
+            .to_string();
+        result.diff = Some(diff);
+    }
+    Ok(result)
+}
+
+fn print(format: OutputFormat, result: &DiffResult) {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string(result).unwrap_or_default()),
+        OutputFormat::Text if result.identical => info!(
+            "{} => {}: identical, md5={}",
+            result.local_path, result.remote_path, result.local_hash
+        ),
+        OutputFormat::Text => match &result.diff {
+            Some(diff) => print!("{}", diff),
+            None if result.remote_missing => println!(
+                "Only local: {} bytes={} md5={}, remote:{} does not exist",
+                result.local_path, result.local_bytes, result.local_hash, result.remote_path
+            ),
+            None => println!(
+                "Binary files differ: {} bytes={} md5={}, remote:{} bytes={} md5={}",
+                result.local_path,
+                result.local_bytes,
+                result.local_hash,
+                result.remote_path,
+                result.remote_bytes,
+                result.remote_hash.as_deref().unwrap_or_default()
+            ),
+        },
+    }
+}
+
+/// Show how local files differ from the remote ones, or from one of their backups.
+/// Exits with `DIFFERENT` when any file differs.
+pub fn diff(args: &DiffArgs, cfg: &Config, format: OutputFormat) -> anyhow::Result<i32> {
+    let Some(mappings) = mapping::select(
+        args.file_mappings.as_deref(),
+        args.manifest.as_deref(),
+        Direction::Push,
+    )?
+    else {
+        usage_bail!("--file-mappings or --manifest is required");
+    };
+    let mut expanded = Vec::new();
+    for mapping in mappings.iter() {
+        expanded.extend(mapping::expand_local_dir(mapping).map_err(UsageError::from_err)?);
+    }
+    if expanded.is_empty() {
+        usage_bail!("no file to diff");
+    }
+    match args.backup {
+        Some(_) => cfg.require(feature::BACKUPS, "diff --backup")?,
+        None => cfg.require(feature::HASHES, "diff")?,
+    }
+
+    let mut code = exit_code::SUCCESS;
+    for mapping in expanded.iter() {
+        match diff_one(args, mapping, cfg) {
+            Ok(result) => {
+                print(format, &result);
+                if !result.identical && code == exit_code::SUCCESS {
+                    code = exit_code::DIFFERENT;
+                }
+            }
+            Err(err) => {
+                match format {
+                    OutputFormat::Json => println!(
+                        "{}",
+                        serde_json::json!({
+                            "local_path": mapping.local,
+                            "remote_path": mapping.remote,
+                            "error": err.to_string(),
+                        })
+                    ),
+                    OutputFormat::Text => {
+                        error!("{} => {}: {}", mapping.local, mapping.remote, err)
+                    }
+                }
+                if code == exit_code::SUCCESS || code == exit_code::DIFFERENT {
+                    code = err.exit_code();
+                }
+            }
+        }
+    }
+    Ok(code)
+}
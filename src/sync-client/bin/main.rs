@@ -1,18 +1,98 @@
+mod chunked;
+mod config;
+mod diff;
+mod mapping;
+mod mirror;
+mod output;
+mod plan;
+mod progress;
+mod retry;
+mod sync;
+mod watch;
+
 use chrono::Local;
-use clap::{arg, command, ArgAction, Args as ClapArgs, Parser, Subcommand};
+use clap::{ArgAction, Args as ClapArgs, Parser, Subcommand};
+use config::{Compression, Config, Sent};
+use indicatif::HumanBytes;
+use indicatif_log_bridge::LogWrapper;
+use lib::apis::capabilities::feature;
+use lib::apis::download::HASH_HEADER;
+use lib::apis::response::ApiResponse;
 use lib::apis::urls;
 use lib::util::file;
-use log::{debug, error, info, LevelFilter};
-use reqwest::blocking::RequestBuilder;
-use reqwest::IntoUrl;
-use std::io::prelude::Write;
+use lib::util::throttle::parse_rate;
+use log::{debug, info, LevelFilter};
+use mapping::{Direction, FileMapping};
+use mirror::DeleteArgs;
+use output::{
+    exit_code, hooks_for, usage_bail, ClientError, OutputFormat, Report, Status, TransferResult,
+    UsageError,
+};
+use plan::Planned;
+use progress::{FileBar, Progress};
+use reqwest::blocking::multipart::{Form, Part};
+use reqwest::blocking::Response;
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use std::io::prelude::{Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Instant;
 use std::{collections::HashMap, fs, path};
 
 #[derive(ClapArgs, Debug, PartialEq)]
-struct Global {
+pub struct Global {
     /// Verbose log
     #[arg(short, long, action = ArgAction::Count)]
     verbose: u8,
+
+    /// Format of the per-file results printed to stdout
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// Number of files transferred concurrently
+    #[arg(short, long, global = true, default_value_t = 1)]
+    pub jobs: usize,
+
+    /// Compress uploads and downloads
+    #[arg(long, global = true, value_enum, default_value_t = Compression::Auto)]
+    pub compress: Compression,
+
+    /// Report what would be created, updated or deleted without writing anything
+    #[arg(long, global = true, default_value_t = false)]
+    pub dry_run: bool,
+
+    /// Files larger than this many bytes are pushed in resumable chunks of this size
+    #[arg(long, global = true, default_value_t = 8 * 1024 * 1024, value_parser = clap::value_parser!(u64).range(1..))]
+    pub chunk_size: u64,
+
+    /// Cap uploads and downloads together at this many bytes per second, e.g. 512K or 10M
+    #[arg(long, global = true, value_parser = parse_rate)]
+    pub bwlimit: Option<u64>,
+
+    /// Retries of a push, pull or ping failing with a connection error, 429, 502, 503 or 504
+    #[arg(long, global = true, default_value_t = 3)]
+    pub retries: u32,
+
+    /// Delay before the first retry, doubled on every retry
+    #[arg(long, global = true, default_value_t = 500)]
+    pub retry_delay_ms: u64,
+
+    /// Longest delay between two retries
+    #[arg(long, global = true, default_value_t = 30_000)]
+    pub retry_max_delay_ms: u64,
+
+    /// Seconds to wait for a connection to the server
+    #[arg(long, global = true, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    pub connect_timeout: u64,
+
+    /// Seconds to wait for an answer of the server, or for more data of a download or an upload to be sent
+    #[arg(long, global = true, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    pub idle_timeout: u64,
+
+    /// Seconds one upload or download may take in total, unlimited by default
+    #[arg(long, global = true, value_parser = clap::value_parser!(u64).range(1..))]
+    pub timeout: Option<u64>,
 }
 
 impl Global {
@@ -35,18 +115,32 @@ enum SubCommand {
 
     #[command(about = "Test operations.")]
     Test(TestArgs),
+
+    #[command(about = "Show how local files differ from the remote ones.")]
+    Diff(diff::DiffArgs),
+
+    #[command(about = "Sync a local and a remote directory in both directions.")]
+    Sync(sync::SyncArgs),
+
+    #[command(about = "Push local files to remote device whenever they change.")]
+    Watch(watch::WatchArgs),
 }
 
 #[derive(ClapArgs, Debug)]
 pub struct TestArgs {
     #[arg(long, default_value_t = false)]
     ping: bool,
+
+    /// Print the server's version, uptime, roots, free space and backup store
+    /// health; exits with 1 when it is not ready to take writes
+    #[arg(long, default_value_t = false)]
+    health: bool,
 }
 
 #[derive(ClapArgs, Debug)]
 pub struct PushArgs {
-    #[arg(long, default_value_t = String::from("safe"))]
-    action: String,
+    #[arg(long, help = "safe or force, defaults to the remote's action or safe")]
+    action: Option<String>,
 
     #[arg(long)]
     local_file_path: Option<String>,
@@ -56,27 +150,60 @@ pub struct PushArgs {
 
     #[arg(
         long,
-        help = "[local_file1]:[remote_file1],[local_file2]:[remote_file2],..."
+        conflicts_with = "manifest",
+        help = "[local_file1]:[remote_file1],[local_file2]:[remote_file2],... (\\, \\: \\\\ escape ',' ':' '\\')"
     )]
     file_mappings: Option<String>,
+
+    #[arg(long, help = "TOML manifest declaring [[mapping]] entries")]
+    manifest: Option<String>,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Upload all files in one batch that is rolled back if any write fails."
+    )]
+    atomic: bool,
+
+    #[command(flatten)]
+    mirror: DeleteArgs,
 }
 
 #[derive(ClapArgs, Debug)]
 pub struct PullArgs {
     #[arg(
         long,
-        help = "[local_file1]:[remote_file1],[local_file2]:[remote_file2],..."
+        conflicts_with = "manifest",
+        help = "[local_file1]:[remote_file1],[local_file2]:[remote_file2],... (\\, \\: \\\\ escape ',' ':' '\\')"
     )]
-    file_mappings: String,
+    file_mappings: Option<String>,
+
+    #[arg(long, help = "TOML manifest declaring [[mapping]] entries")]
+    manifest: Option<String>,
+
+    #[command(flatten)]
+    mirror: DeleteArgs,
 }
 
 #[derive(Parser, Debug)]
-struct Args {
+pub struct Args {
     #[command(flatten)]
-    global: Global,
+    pub global: Global,
 
-    #[arg(long)]
-    addr: String,
+    #[arg(long, help = "Remote address, overrides the one of --remote")]
+    addr: Option<String>,
+
+    #[arg(long, help = "Named remote of the config file")]
+    remote: Option<String>,
+
+    #[arg(
+        long,
+        help = "Config file, defaults to ~/.config/sync-file/config.toml"
+    )]
+    config: Option<String>,
+
+    #[arg(long, help = "Bearer token sent to the remote")]
+    token: Option<String>,
 
     #[arg(long, help = "Specify HOST in request header")]
     host: Option<String>,
@@ -92,255 +219,568 @@ struct Args {
     command: SubCommand,
 }
 
-fn panic_if_not_expect_file(filename: &str) {
+fn validate_local_file(filename: &str) -> Result<(), String> {
     let path_obj = path::Path::new(filename);
     if !path_obj.exists() {
-        panic!("not found local file({:?})", &path_obj);
+        return Err(format!("not found local file({:?})", &path_obj));
     }
 
     if !path_obj.is_file() {
-        panic!(
+        return Err(format!(
             "local_file_path is not a file({})",
             path_obj.to_str().unwrap_or_default()
-        );
+        ));
+    }
+    Ok(())
+}
+
+/// `bar` is the progress bar of earlier attempts to send this file, if any.
+fn file_part(
+    local_file: &str,
+    cfg: &Config,
+    sent: &Sent,
+    bar: &mut Option<Arc<FileBar>>,
+) -> Result<Part, ClientError> {
+    validate_local_file(local_file).map_err(ClientError::Local)?;
+    let file_strem = fs::read(local_file).map_err(|e| ClientError::Local(e.to_string()))?;
+
+    let mut headers = HeaderMap::new();
+    let mut body = file_strem;
+    if let Some(encoding) = cfg.upload_encoding(local_file, &body) {
+        let encoded = encoding
+            .encode(&body)
+            .map_err(|e| ClientError::Local(e.to_string()))?;
+        // `auto` keeps the raw bytes when compressing does not help.
+        if cfg.compress == Compression::On || encoded.len() < body.len() {
+            debug!(
+                "{} {}: {} => {} bytes",
+                encoding.as_str(),
+                local_file,
+                body.len(),
+                encoded.len()
+            );
+            headers.insert(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_static(encoding.as_str()),
+            );
+            body = encoded;
+        }
     }
+    let len = body.len() as u64;
+    let bar = cfg.attempt_bar(bar, local_file, Some(len));
+    Ok(
+        Part::reader_with_length(cfg.body_reader(body, bar, sent), len)
+            .file_name("file")
+            .mime_str("text/plain")
+            .unwrap()
+            .headers(headers),
+    )
 }
 
-fn validate_for_upload(args: &PushArgs) {
-    panic_if_not_expect_file(&args.local_file_path.clone().unwrap_or_default());
+fn upload_file(
+    mapping: &FileMapping,
+    action: &str,
+    cfg: &Config,
+) -> Result<ApiResponse, ClientError> {
+    validate_local_file(&mapping.local).map_err(ClientError::Local)?;
+    let size = fs::metadata(&mapping.local)
+        .map_err(|e| ClientError::Local(e.to_string()))?
+        .len();
+    if size > cfg.chunk_size && cfg.capabilities().supports(feature::RESUMABLE) {
+        return chunked::upload(mapping, action, size, cfg);
+    }
+    // Uploading the same content twice does not change the result, so retrying is safe.
+    let what = format!("push {} => {}", mapping.local, mapping.remote);
+    let mut bar = None;
+    cfg.retry
+        .run(&what, || upload_multipart(mapping, action, cfg, &mut bar))
 }
 
-fn upload_file(args: &PushArgs, cfg: &Config) -> anyhow::Result<()> {
-    let file_strem = fs::read(args.local_file_path.clone().unwrap().as_str()).unwrap();
-    let file_part = reqwest::blocking::multipart::Part::bytes(file_strem)
-        .file_name("file")
-        .mime_str("text/plain")
-        .unwrap();
-    let multipart_form = reqwest::blocking::multipart::Form::new()
-        .text("action", args.action.clone())
-        .text("target_file_path", args.remote_file_path.clone().unwrap())
-        .part("file", file_part);
+fn upload_multipart(
+    mapping: &FileMapping,
+    action: &str,
+    cfg: &Config,
+    bar: &mut Option<Arc<FileBar>>,
+) -> Result<ApiResponse, ClientError> {
+    let sent = Sent::default();
+    let file_part = file_part(&mapping.local, cfg, &sent, bar)?;
+    let mut multipart_form = Form::new()
+        .text("action", action.to_string())
+        .text("target_file_path", mapping.remote.clone());
+    if let Some(mode) = &mapping.mode {
+        multipart_form = multipart_form.text("mode", mode.clone());
+    }
+    let multipart_form = multipart_form.part("file", file_part);
 
     let url = urls::UPLOAD_URL_V1!(cfg.protocol.data(), cfg.addr);
-    let request = cfg.make_request(url)?.multipart(multipart_form);
-    match request.send() {
-        Err(err) => {
-            error!("send request err: {}", err);
-            std::process::exit(127);
-        }
-        Ok(resp) if !resp.status().is_success() => {
-            error!(
-                "send request err: code={}, {}",
-                resp.status(),
-                resp.text().unwrap_or_default()
-            );
-            std::process::exit(127);
-        }
-        Ok(resp) => {
-            info!("{}", resp.text().unwrap());
-            std::process::exit(0);
-        }
+    let request = cfg.make_upload(url).multipart(multipart_form);
+    parse_response(cfg.send_upload(request, &sent)?)
+}
+
+/// Turn a response into its envelope, or the typed error the server reported.
+fn parse_response(resp: Response) -> Result<ApiResponse, ClientError> {
+    let status = resp.status().as_u16();
+    let headers = resp.headers().clone();
+    let text = resp.text()?;
+    if !(200..300).contains(&status) {
+        return Err(ClientError::from_response(status, &headers, &text));
     }
+    serde_json::from_str(&text).map_err(|_| ClientError::from_response(status, &headers, &text))
+}
 
-    #[allow(unreachable_code)]
-    Ok(())
+fn push_one(mapping: &FileMapping, action: &str, cfg: &Config) -> TransferResult {
+    let start = Instant::now();
+    let result = match upload_file(mapping, mapping.action_or(action), cfg) {
+        Err(err) => TransferResult::failed(&mapping.local, &mapping.remote, err),
+        Ok(body) => upload_result(&mapping.local, &mapping.remote, body),
+    };
+    result.with_duration(start.elapsed())
 }
 
-// mappings format: local_file1:remote_file1,local_file2:remote_file2,...
-// return {
-//   local_file1: remote_file1,
-//   local_file2: remote_file2,
-// }
-fn parse_file_mappings(mappings: &str) -> anyhow::Result<HashMap<String, String>> {
-    let mut m: HashMap<String, String> = HashMap::new();
-    mappings.split(",").for_each(|chunk| {
-        let kv_list: Vec<&str> = chunk.split(":").collect();
-        let (local_file, remote_file) = (kv_list[0], kv_list[1]);
-        m.insert(local_file.into(), remote_file.into());
-    });
-    Ok(m)
+fn upload_result(local_file: &str, remote_file: &str, body: ApiResponse) -> TransferResult {
+    let status = if body.changed.unwrap_or(true) {
+        Status::Uploaded
+    } else {
+        Status::Unchanged
+    };
+    TransferResult {
+        bytes: body.bytes.unwrap_or_default(),
+        hash: body.hash,
+        backup: body.backup,
+        hooks: hooks_for(&body.hooks, remote_file),
+        ..TransferResult::new(local_file, remote_file, status)
+    }
 }
 
-fn upload_file_mappings(args: &PushArgs, cfg: &Config) -> anyhow::Result<()> {
-    // parse file mappings
-    let mappings = parse_file_mappings(args.file_mappings.as_ref().unwrap())?;
+/// Mappings of a push, from the manifest, `--file-mappings` or the single file flags.
+fn push_mappings(args: &PushArgs) -> anyhow::Result<Vec<FileMapping>> {
+    let selected = mapping::select(
+        args.file_mappings.as_deref(),
+        args.manifest.as_deref(),
+        Direction::Push,
+    )?;
+    let mappings = if let Some(selected) = selected {
+        selected
+    } else {
+        let (Some(local_file), Some(remote_file)) = (&args.local_file_path, &args.remote_file_path)
+        else {
+            usage_bail!(
+                "--local-file-path and --remote-file-path are required without --file-mappings"
+            );
+        };
+        if let Err(err) = validate_local_file(local_file) {
+            usage_bail!("{}", err);
+        }
+        vec![FileMapping::new(local_file, remote_file)]
+    };
+    Ok(mappings)
+}
 
-    // http client
-    let url = urls::UPLOAD_URL_V1!(cfg.protocol.data(), cfg.addr);
+fn upload_file_mappings(args: &PushArgs, cfg: &Config, report: &mut Report) -> anyhow::Result<()> {
+    let mappings = push_mappings(args)?;
+    mirror::require_remote_delete(&args.mirror, cfg)?;
+    let mut expanded = Vec::new();
+    for mapping in mappings.iter() {
+        expanded.extend(mapping::expand_local_dir(mapping).map_err(UsageError::from_err)?);
+    }
+    if expanded.is_empty() && !args.mirror.delete {
+        usage_bail!("no file to push");
+    }
 
-    let mut fail_list = Vec::new();
+    let action = args.action.as_deref().unwrap_or(&cfg.action);
+    if cfg.dry_run {
+        let planned: Vec<Planned> = expanded.iter().map(|m| Planned::Push(m, action)).collect();
+        plan::report(&planned, cfg, report)?;
+    } else if args.atomic {
+        push_batch(action, &expanded, cfg, report);
+    } else {
+        run_parallel(&expanded, cfg.jobs, report, |mapping| {
+            push_one(mapping, action, cfg)
+        });
+    }
 
-    for (local_file, remote_file) in mappings.into_iter() {
-        let file_strem = fs::read(&local_file).unwrap();
-        let file_part = reqwest::blocking::multipart::Part::bytes(file_strem)
-            .file_name("file")
-            .mime_str("text/plain")
-            .unwrap();
-        let multipart_form = reqwest::blocking::multipart::Form::new()
-            .text("action", args.action.clone())
-            .text("target_file_path", remote_file)
-            .part("file", file_part);
-
-        // make http request
-        let request = cfg.make_request(&url)?.multipart(multipart_form);
-        match request.send() {
-            Err(err) => {
-                fail_list.push(format!("{} => {}", local_file, err));
+    if args.mirror.delete {
+        let mut deletions = Vec::new();
+        for mapping in mappings.iter() {
+            if path::Path::new(&mapping.local).is_dir() {
+                deletions.extend(
+                    mirror::remote_extras(mapping, &expanded, cfg)
+                        .map_err(|e| anyhow::anyhow!("list {} err: {}", mapping.remote, e))?,
+                );
             }
-            Ok(resp) if !resp.status().is_success() => {
-                fail_list.push(format!("{} => {}", local_file, resp.status()));
+        }
+        mirror::delete(&args.mirror, &deletions, action, cfg, report)?;
+    }
+    Ok(())
+}
+
+fn upload_batch(
+    action: &str,
+    mappings: &[FileMapping],
+    cfg: &Config,
+) -> Result<ApiResponse, ClientError> {
+    if !cfg.capabilities().supports(feature::BATCH) {
+        return Err(ClientError::Local(
+            "the server does not support --atomic batch uploads".to_string(),
+        ));
+    }
+    let sent = Sent::default();
+    let mut multipart_form = Form::new().text("action", action.to_string());
+    for mapping in mappings.iter() {
+        multipart_form = multipart_form.text("target_file_path", mapping.remote.clone());
+        if let Some(action) = &mapping.action {
+            multipart_form = multipart_form.text("action", action.clone());
+        }
+        if let Some(mode) = &mapping.mode {
+            multipart_form = multipart_form.text("mode", mode.clone());
+        }
+        multipart_form =
+            multipart_form.part("file", file_part(&mapping.local, cfg, &sent, &mut None)?);
+    }
+
+    let url = urls::BATCH_URL_V1!(cfg.protocol.data(), cfg.addr);
+    let request = cfg.make_upload(url).multipart(multipart_form);
+    parse_response(cfg.send_upload(request, &sent)?)
+}
+
+/// Push every mapping in one all-or-nothing request.
+fn push_batch(action: &str, mappings: &[FileMapping], cfg: &Config, report: &mut Report) {
+    let start = Instant::now();
+    match upload_batch(action, mappings, cfg) {
+        Err(err) => {
+            for mapping in mappings.iter() {
+                report.push(
+                    TransferResult::failed(&mapping.local, &mapping.remote, err.clone())
+                        .with_duration(start.elapsed()),
+                );
             }
-            Ok(resp) => {
-                info!("{} => {}", local_file, resp.text().unwrap());
+        }
+        Ok(body) => {
+            for (mapping, file) in mappings.iter().zip(body.files) {
+                let result = TransferResult {
+                    hooks: hooks_for(&body.hooks, &mapping.remote),
+                    ..upload_result(&mapping.local, &mapping.remote, file)
+                };
+                report.push(result.with_duration(start.elapsed()));
             }
         }
     }
+}
 
-    if !fail_list.is_empty() {
-        anyhow::bail!("{}", fail_list.join("\n"));
+/// Content and md5 of a remote file.
+fn fetch_file(remote_path: &str, cfg: &Config) -> Result<(Vec<u8>, Option<String>), ClientError> {
+    let mut bar = None;
+    cfg.retry.run(&format!("pull {}", remote_path), || {
+        fetch_once(remote_path, cfg, &mut bar)
+    })
+}
+
+/// `bar` is the progress bar of earlier attempts, if any.
+fn fetch_once(
+    remote_path: &str,
+    cfg: &Config,
+    bar: &mut Option<Arc<FileBar>>,
+) -> Result<(Vec<u8>, Option<String>), ClientError> {
+    let mut m = HashMap::new();
+    m.insert("file_path", remote_path);
+
+    let url = urls::DOWNLOAD_URL_V1!(cfg.protocol.data(), cfg.addr);
+    let request = cfg.make_request(url).json(&m);
+
+    let started = Instant::now();
+    let resp = request.send()?;
+    let status = resp.status().as_u16();
+    if !resp.status().is_success() {
+        let headers = resp.headers().clone();
+        let text = resp.text()?;
+        return Err(ClientError::from_response(status, &headers, &text));
     }
+    let hash = resp
+        .headers()
+        .get(HASH_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+    let mut content = Vec::new();
+    let bar = cfg.attempt_bar(bar, remote_path, resp.content_length());
+    cfg.response_reader(resp, started, bar)
+        .read_to_end(&mut content)
+        .map_err(|e| ClientError::Connection(e.to_string()))?;
+    Ok((content, hash))
+}
 
-    Ok(())
+fn download_file(
+    local_file: &str,
+    remote_path: &str,
+    cfg: &Config,
+) -> Result<TransferResult, ClientError> {
+    // 1. download remote file
+    let (content, hash) = fetch_file(remote_path, cfg)?;
+
+    // 2. write to local file
+    file::create_and_write(local_file, &content).map_err(|e| ClientError::Local(e.to_string()))?;
+    Ok(TransferResult {
+        bytes: content.len() as u64,
+        hash,
+        ..TransferResult::new(local_file, remote_path, Status::Downloaded)
+    })
+}
+
+fn pull_one(mapping: &FileMapping, cfg: &Config) -> TransferResult {
+    let start = Instant::now();
+    download_file(&mapping.local, &mapping.remote, cfg)
+        .unwrap_or_else(|err| TransferResult::failed(&mapping.local, &mapping.remote, err))
+        .with_duration(start.elapsed())
 }
 
-fn download_file_mappings(args: &PullArgs, cfg: &Config) -> anyhow::Result<()> {
-    let mappings = parse_file_mappings(&args.file_mappings)?;
+fn download_file_mappings(
+    args: &PullArgs,
+    cfg: &Config,
+    report: &mut Report,
+) -> anyhow::Result<()> {
+    let Some(mappings) = mapping::select(
+        args.file_mappings.as_deref(),
+        args.manifest.as_deref(),
+        Direction::Pull,
+    )?
+    else {
+        usage_bail!("--file-mappings or --manifest is required");
+    };
     if mappings.is_empty() {
-        anyhow::bail!("--file-mapping is invalid: {}", args.file_mappings);
+        usage_bail!("no file to pull");
     };
 
-    let mut fail_list = Vec::new();
-
-    for (local_file, remote_path) in mappings.iter() {
-        // 1. download remote file
-        let mut m = HashMap::new();
-        m.insert("file_path", remote_path);
-
-        let url = urls::DOWNLOAD_URL_V1!(cfg.protocol.data(), cfg.addr);
-        let request = cfg.make_request(url)?.json(&m);
-
-        match request.send() {
-            Err(err) => {
-                fail_list.push(format!("{} => {}", local_file, err));
-            }
-            Ok(resp) if !resp.status().is_success() => {
-                fail_list.push(format!("{} => {}", local_file, resp.status()));
-            }
-            Ok(resp) => {
-                // 2. write to local file
-                file::create_and_write(local_file, resp.bytes()?)?;
+    if args.mirror.delete {
+        cfg.require(feature::LIST, "--delete")?;
+    }
+    // A remote directory is pulled file by file.
+    let mut expanded = Vec::new();
+    let mut deletions = Vec::new();
+    for mapping in mappings.iter() {
+        match mirror::expand_remote_dir(mapping, cfg) {
+            Ok(Some(files)) => {
+                if args.mirror.delete {
+                    deletions.extend(mirror::local_extras(mapping, &files)?);
+                }
+                expanded.extend(files);
             }
+            Ok(None) => expanded.push(mapping.clone()),
+            Err(err) => report.push(TransferResult::failed(&mapping.local, &mapping.remote, err)),
         }
     }
 
-    if !fail_list.is_empty() {
-        anyhow::bail!("{}", fail_list.join("\n"));
+    if cfg.dry_run {
+        let planned: Vec<Planned> = expanded.iter().map(Planned::Pull).collect();
+        plan::report(&planned, cfg, report)?;
+    } else {
+        run_parallel(&expanded, cfg.jobs, report, |mapping| {
+            pull_one(mapping, cfg)
+        });
     }
 
+    if args.mirror.delete {
+        mirror::delete(&args.mirror, &deletions, &cfg.action, cfg, report)?;
+    }
     Ok(())
 }
 
-fn ping_server(cfg: &Config) -> anyhow::Result<()> {
-    let url = urls::PING_URL_V1!(&cfg.protocol.data(), cfg.addr);
-    let client = cfg.protocol.new_client()?;
-    match client.get(url).send() {
-        Err(err) => {
-            error!("ping server err: {}", err);
-            std::process::exit(127);
+/// Run `task` for every item on up to `jobs` threads, reporting results in input order.
+fn run_parallel<T, F>(items: &[T], jobs: usize, report: &mut Report, task: F)
+where
+    T: Sync,
+    F: Fn(&T) -> TransferResult + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+    thread::scope(|s| {
+        for _ in 0..jobs.clamp(1, items.len().max(1)) {
+            let tx = tx.clone();
+            let (next, task) = (&next, &task);
+            s.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(index) else {
+                    break;
+                };
+                if tx.send((index, task(item))).is_err() {
+                    break;
+                }
+            });
         }
-        Ok(resp) if !resp.status().is_success() => {
-            error!(
-                "ping server err: code={}, {}",
-                resp.status(),
-                resp.text().unwrap_or_default()
-            );
-            std::process::exit(127);
-        }
-        Ok(resp) => {
-            info!(
-                "code: {}, {}",
-                resp.status(),
-                resp.text().unwrap_or_default()
-            );
-            std::process::exit(0);
+        drop(tx);
+        // Results finish in any order but are reported in input order, each
+        // as soon as the ones before it are in.
+        let mut pending: Vec<Option<TransferResult>> = items.iter().map(|_| None).collect();
+        let mut reported = 0;
+        for (index, result) in rx {
+            pending[index] = Some(result);
+            while let Some(result) = pending.get_mut(reported).and_then(Option::take) {
+                report.push(result);
+                reported += 1;
+            }
         }
-    }
+    });
 }
 
-enum ReqProtocol {
-    Http(String),
-    Https(String),
-}
+fn ping_server(cfg: &Config, format: OutputFormat) -> i32 {
+    let url = urls::PING_URL_V1!(&cfg.protocol.data(), cfg.addr);
+    let result = cfg.retry.run("ping", || {
+        cfg.make_get(&url)
+            .send()
+            .map_err(ClientError::from)
+            .and_then(parse_response)
+    });
 
-impl ReqProtocol {
-    fn new(protocol: impl Into<String>) -> Self {
-        let protocol: String = protocol.into();
-        let protocol: &str = &protocol.to_lowercase();
-        match protocol {
-            "http" => Self::Http(protocol.to_string()),
-            "https" => Self::Https(protocol.to_string()),
-            _ => unreachable!(),
+    let (code, value) = match result {
+        Err(err) => (
+            err.exit_code(),
+            serde_json::json!({ "status": "error", "error": err.to_string() }),
+        ),
+        Ok(body) => (
+            exit_code::SUCCESS,
+            serde_json::json!({ "status": "ok", "message": body.message }),
+        ),
+    };
+    match format {
+        OutputFormat::Json => println!("{}", value),
+        OutputFormat::Text if code == exit_code::SUCCESS => {
+            info!("{}", value["message"].as_str().unwrap_or_default())
         }
+        OutputFormat::Text => log::error!(
+            "ping server err: {}",
+            value["error"].as_str().unwrap_or_default()
+        ),
     }
+    code
+}
 
-    fn data(&self) -> String {
-        match self {
-            Self::Http(data) => data.clone(),
-            Self::Https(data) => data.clone(),
+fn check_health(cfg: &Config, format: OutputFormat) -> i32 {
+    let url = urls::HEALTHZ_URL!(&cfg.protocol.data(), cfg.addr);
+    let result = cfg.retry.run("health", || {
+        cfg.make_get(&url)
+            .send()
+            .map_err(ClientError::from)
+            .and_then(parse_response)
+    });
+    let health = result.and_then(|body| {
+        body.health.ok_or_else(|| {
+            ClientError::Local("server answered no health, is it too old?".to_string())
+        })
+    });
+    let health = match health {
+        Ok(health) => health,
+        Err(err) => {
+            match format {
+                OutputFormat::Json => println!(
+                    "{}",
+                    serde_json::json!({ "status": "error", "error": err.to_string() })
+                ),
+                OutputFormat::Text => log::error!("check health err: {}", err),
+            }
+            return err.exit_code();
         }
-    }
-
-    fn new_client(&self) -> anyhow::Result<reqwest::blocking::Client> {
-        match self {
-            ReqProtocol::Http(_) => reqwest::blocking::Client::builder()
-                .build()
-                .map_err(anyhow::Error::from),
-            ReqProtocol::Https(_) => reqwest::blocking::Client::builder()
-                .danger_accept_invalid_certs(true)
-                .build()
-                .map_err(anyhow::Error::from),
+    };
+    let code = if health.ready {
+        exit_code::SUCCESS
+    } else {
+        exit_code::FAILURE
+    };
+    let status = if health.ready { "ready" } else { "not ready" };
+
+    match format {
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::json!({ "status": status, "health": health })
+        ),
+        OutputFormat::Text => {
+            info!(
+                "version {}, up {}s, {}",
+                health.version, health.uptime_secs, status
+            );
+            if health.roots.is_empty() {
+                info!("no roots configured");
+            }
+            for root in health.roots.iter() {
+                let free = root
+                    .free_bytes
+                    .map_or("unknown".to_string(), |free| HumanBytes(free).to_string());
+                info!(
+                    "root {}: {}, {} free{}{}",
+                    root.path,
+                    if root.writable {
+                        "writable"
+                    } else {
+                        "not writable"
+                    },
+                    free,
+                    if root.low_space { " (low)" } else { "" },
+                    root.error
+                        .as_ref()
+                        .map_or(String::new(), |err| format!(", {}", err))
+                );
+            }
+            let backups = &health.backups;
+            info!(
+                "backups: {}, {} used, keep {}{}",
+                if backups.ok { "ok" } else { "failing" },
+                HumanBytes(backups.bytes),
+                backups
+                    .keep
+                    .map_or("all".to_string(), |keep| keep.to_string()),
+                backups
+                    .error
+                    .as_ref()
+                    .map_or(String::new(), |err| format!(", {}", err))
+            );
         }
     }
+    code
 }
 
-struct Config {
-    addr: String,
-    header_host: Option<String>,
-    protocol: ReqProtocol,
-}
+fn run(args: Args, progress: Option<Arc<Progress>>) -> anyhow::Result<i32> {
+    let cfg = Config::new(&args, progress.clone())?;
+    let mut report = Report::new(args.global.output);
 
-impl Config {
-    fn make_request<U: IntoUrl>(&self, url: U) -> anyhow::Result<RequestBuilder> {
-        let client = self.protocol.new_client()?;
-        let request = if self.header_host.is_some() {
-            client
-                .post(url)
-                .header("Host", self.header_host.clone().unwrap())
-        } else {
-            client.post(url)
-        };
-        Ok(request)
+    match args.command {
+        SubCommand::Test(test_args) => {
+            if test_args.ping {
+                return Ok(ping_server(&cfg, args.global.output));
+            }
+            if test_args.health {
+                return Ok(check_health(&cfg, args.global.output));
+            }
+            usage_bail!("nothing to test, try --ping or --health");
+        }
+        SubCommand::Pull(pull_args) => {
+            download_file_mappings(&pull_args, &cfg, &mut report)?;
+        }
+        SubCommand::Push(push_args) => {
+            cfg.check_compression()?;
+            upload_file_mappings(&push_args, &cfg, &mut report)?;
+        }
+        SubCommand::Diff(diff_args) => {
+            return diff::diff(&diff_args, &cfg, args.global.output);
+        }
+        SubCommand::Sync(sync_args) => {
+            cfg.check_compression()?;
+            sync::sync(&sync_args, &cfg, &mut report)?;
+        }
+        SubCommand::Watch(watch_args) => {
+            cfg.check_compression()?;
+            return watch::watch(&watch_args, &cfg, args.global.output);
+        }
     }
-}
 
-impl Config {
-    fn new(args: &Args) -> Self {
-        Config {
-            addr: args.addr.clone(),
-            header_host: args.host.clone(),
-            protocol: ReqProtocol::new(if args.enable_insecure_ssl {
-                "https"
-            } else {
-                "http"
-            }),
-        }
+    if let Some(progress) = &progress {
+        progress.finish();
     }
+    report.finish();
+    Ok(report.exit_code())
 }
 
-fn main() -> anyhow::Result<()> {
+fn main() {
     let args = Args::parse();
 
-    env_logger::builder()
+    let progress = Progress::enabled(args.global.output).then(|| Arc::new(Progress::new()));
+    let logger = env_logger::builder()
         .format(move |buf, record| {
             let level = record.level();
             let style = buf.default_level_style(level);
@@ -352,34 +792,28 @@ fn main() -> anyhow::Result<()> {
             ))
         })
         .filter_level(args.global.log_filter_level())
-        .init();
+        .build();
+    log::set_max_level(logger.filter());
+    match &progress {
+        // Print log lines above the progress bars.
+        Some(progress) => LogWrapper::new(progress.multi().clone(), logger)
+            .try_init()
+            .unwrap(),
+        None => log::set_boxed_logger(Box::new(logger)).unwrap(),
+    }
     debug!("args: {:?}", args);
 
-    let cfg = Config::new(&args);
-
-    match args.command {
-        SubCommand::Test(test_args) => {
-            if test_args.ping {
-                ping_server(&cfg)?;
-            }
-        }
-        SubCommand::Pull(pull_args) => {
-            download_file_mappings(&pull_args, &cfg)?;
-        }
-        SubCommand::Push(push_args) => {
-            if push_args.file_mappings.is_some() {
-                upload_file_mappings(&push_args, &cfg)?;
+    let format = args.global.output;
+    let code = match run(args, progress) {
+        Ok(code) => code,
+        Err(err) => {
+            output::print_error(format, &err);
+            if err.is::<output::UsageError>() {
+                exit_code::USAGE
             } else {
-                validate_for_upload(&push_args);
-                upload_file(&push_args, &cfg)?;
+                exit_code::FAILURE
             }
         }
-        #[allow(unreachable_patterns)]
-        _ => {
-            error!("Invalid subcommand");
-            std::process::exit(127);
-        }
-    }
-
-    Ok(())
+    };
+    std::process::exit(code);
 }
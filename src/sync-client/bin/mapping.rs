@@ -0,0 +1,301 @@
+use std::{fs, path};
+
+use glob::Pattern;
+use lib::util::schema::parse_mode;
+
+use crate::output::UsageError;
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Push,
+    Pull,
+}
+
+/// One local/remote pair, either from `--file-mappings` or a manifest entry.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct FileMapping {
+    pub local: String,
+    pub remote: String,
+    /// `safe` or `force`, falls back to `--action`.
+    #[serde(default)]
+    pub action: Option<String>,
+    /// Octal permission bits applied to the written file, e.g. `"0644"`.
+    #[serde(default)]
+    pub mode: Option<String>,
+    /// Glob patterns skipped when `local` is a directory.
+    #[serde(default)]
+    pub excludes: Vec<String>,
+    /// Limits the entry to `push` or `pull`; both when unset.
+    #[serde(default)]
+    pub direction: Option<Direction>,
+}
+
+impl FileMapping {
+    pub fn new(local: impl Into<String>, remote: impl Into<String>) -> Self {
+        FileMapping {
+            local: local.into(),
+            remote: remote.into(),
+            action: None,
+            mode: None,
+            excludes: Vec::new(),
+            direction: None,
+        }
+    }
+
+    pub fn applies_to(&self, direction: Direction) -> bool {
+        self.direction.is_none_or(|d| d == direction)
+    }
+
+    pub fn action_or<'a>(&'a self, default: &'a str) -> &'a str {
+        self.action.as_deref().unwrap_or(default)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.local.is_empty() || self.remote.is_empty() {
+            anyhow::bail!("local and remote path must not be empty");
+        }
+        if let Some(action) = &self.action {
+            if action != "safe" && action != "force" {
+                anyhow::bail!("unknown action '{}', expect safe or force", action);
+            }
+        }
+        if let Some(mode) = &self.mode {
+            if parse_mode(mode).is_none() {
+                anyhow::bail!("mode '{}' is not an octal permission", mode);
+            }
+        }
+        for exclude in self.excludes.iter() {
+            Pattern::new(exclude)
+                .map_err(|e| anyhow::anyhow!("invalid exclude '{}': {}", exclude, e))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[serde(default, rename = "mapping")]
+    mappings: Vec<FileMapping>,
+}
+
+// mappings format: local_file1:remote_file1,local_file2:remote_file2,...
+// `\,`, `\:` and `\\` escape a literal comma, colon and backslash.
+// Entries keep the order they were given in, duplicates included.
+pub fn parse_file_mappings(mappings: &str) -> anyhow::Result<Vec<FileMapping>> {
+    let mut result = Vec::new();
+    for (index, entry) in split_unescaped(mappings, ',')?.into_iter().enumerate() {
+        let parts = split_unescaped(&entry, ':')?;
+        if parts.len() != 2 {
+            anyhow::bail!(
+                "mapping #{} '{}' should be [local_file]:[remote_file], escape ':' as '\\:'",
+                index + 1,
+                entry
+            );
+        }
+        let mapping = FileMapping::new(unescape(&parts[0]), unescape(&parts[1]));
+        mapping
+            .validate()
+            .map_err(|e| anyhow::anyhow!("mapping #{} '{}': {}", index + 1, entry, e))?;
+        result.push(mapping);
+    }
+    Ok(result)
+}
+
+/// Split on `sep` unless it is escaped, keeping escapes for the next pass.
+fn split_unescaped(s: &str, sep: char) -> anyhow::Result<Vec<String>> {
+    let mut parts = vec![String::new()];
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                let Some(next) = chars.next() else {
+                    anyhow::bail!("'{}' ends with a dangling '\\'", s);
+                };
+                let current = parts.last_mut().unwrap();
+                current.push('\\');
+                current.push(next);
+            }
+            c if c == sep => parts.push(String::new()),
+            c => parts.last_mut().unwrap().push(c),
+        }
+    }
+    Ok(parts)
+}
+
+fn unescape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => result.extend(chars.next()),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Mappings of `--manifest` or `--file-mappings` that apply to `direction`,
+/// `None` when neither is given. Bad input is reported as a usage error.
+pub fn select(
+    file_mappings: Option<&str>,
+    manifest: Option<&str>,
+    direction: Direction,
+) -> anyhow::Result<Option<Vec<FileMapping>>> {
+    let mappings = match (manifest, file_mappings) {
+        (Some(manifest), _) => load_manifest(manifest)
+            .map_err(UsageError::from_err)?
+            .into_iter()
+            .filter(|m| m.applies_to(direction))
+            .collect(),
+        (None, Some(file_mappings)) => {
+            parse_file_mappings(file_mappings).map_err(UsageError::from_err)?
+        }
+        (None, None) => return Ok(None),
+    };
+    Ok(Some(mappings))
+}
+
+/// Load the `[[mapping]]` entries of a TOML manifest, in file order.
+pub fn load_manifest(manifest: &str) -> anyhow::Result<Vec<FileMapping>> {
+    let content = fs::read_to_string(manifest)
+        .map_err(|e| anyhow::anyhow!("read manifest {} err: {}", manifest, e))?;
+    let parsed: Manifest = toml::from_str(&content)
+        .map_err(|e| anyhow::anyhow!("parse manifest {} err: {}", manifest, e))?;
+    for (index, mapping) in parsed.mappings.iter().enumerate() {
+        mapping
+            .validate()
+            .map_err(|e| anyhow::anyhow!("{} mapping #{}: {}", manifest, index + 1, e))?;
+    }
+    Ok(parsed.mappings)
+}
+
+/// Expand a mapping whose local side is a directory into one mapping per
+/// file below it, skipping anything matched by `excludes`.
+pub fn expand_local_dir(mapping: &FileMapping) -> anyhow::Result<Vec<FileMapping>> {
+    if !path::Path::new(&mapping.local).is_dir() {
+        return Ok(vec![mapping.clone()]);
+    }
+
+    let excludes: Vec<Pattern> = mapping
+        .excludes
+        .iter()
+        .filter_map(|e| Pattern::new(e).ok())
+        .collect();
+    let mut files = Vec::new();
+    walk_dir(path::Path::new(&mapping.local), "", &excludes, &mut files)?;
+    files.sort();
+
+    Ok(files
+        .into_iter()
+        .map(|relative| FileMapping {
+            local: format!("{}/{}", mapping.local.trim_end_matches('/'), relative),
+            remote: format!("{}/{}", mapping.remote.trim_end_matches('/'), relative),
+            excludes: Vec::new(),
+            ..mapping.clone()
+        })
+        .collect())
+}
+
+/// Whether `relative` or any directory above it matches an exclude, either
+/// by its path or by its own name.
+pub fn is_excluded(excludes: &[Pattern], relative: &str) -> bool {
+    let mut prefix = String::new();
+    for name in relative.split('/') {
+        if !prefix.is_empty() {
+            prefix.push('/');
+        }
+        prefix.push_str(name);
+        if excludes
+            .iter()
+            .any(|p| p.matches(&prefix) || p.matches(name))
+        {
+            return true;
+        }
+    }
+    false
+}
+
+fn walk_dir(
+    dir: &path::Path,
+    prefix: &str,
+    excludes: &[Pattern],
+    files: &mut Vec<String>,
+) -> anyhow::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let relative = if prefix.is_empty() {
+            name
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        if is_excluded(excludes, &relative) {
+            continue;
+        }
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            walk_dir(&entry.path(), &relative, excludes, files)?;
+        } else if file_type.is_file() {
+            files.push(relative);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(mappings: &str) -> Vec<(String, String)> {
+        parse_file_mappings(mappings)
+            .unwrap()
+            .into_iter()
+            .map(|m| (m.local, m.remote))
+            .collect()
+    }
+
+    fn pair(local: &str, remote: &str) -> (String, String) {
+        (local.to_string(), remote.to_string())
+    }
+
+    #[test]
+    fn keeps_order_and_duplicates() {
+        assert_eq!(
+            pairs("b:/r/b,a:/r/a,b:/r/b"),
+            vec![pair("b", "/r/b"), pair("a", "/r/a"), pair("b", "/r/b")]
+        );
+    }
+
+    #[test]
+    fn unescapes_separators() {
+        assert_eq!(
+            pairs(r"C\:/a\,b.txt:/r/a\,b,x\\y:/r/c\:d"),
+            vec![pair("C:/a,b.txt", "/r/a,b"), pair(r"x\y", "/r/c:d")]
+        );
+        // Any other escaped char stands for itself.
+        assert_eq!(pairs(r"\a:\b"), vec![pair("a", "b")]);
+    }
+
+    #[test]
+    fn rejects_empty_segments() {
+        for mappings in ["", ",", "a:", ":b", ":", "a:b,", ",a:b", "a:b,,c:d"] {
+            assert!(
+                parse_file_mappings(mappings).is_err(),
+                "'{}' should fail",
+                mappings
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_bad_separators() {
+        let err = parse_file_mappings("a:b,c:d:e").unwrap_err().to_string();
+        assert!(err.starts_with("mapping #2 'c:d:e'"), "{}", err);
+        assert!(parse_file_mappings("ab").is_err());
+        assert!(parse_file_mappings(r"a:b\").is_err());
+    }
+}
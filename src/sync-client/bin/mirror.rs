@@ -0,0 +1,253 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use clap::Args as ClapArgs;
+use glob::Pattern;
+use lib::apis::capabilities::feature;
+use lib::apis::response::ApiResponse;
+use lib::apis::urls;
+use log::{info, warn};
+
+use crate::config::Config;
+use crate::mapping::{self, FileMapping};
+use crate::output::{usage_bail, ClientError, Report, Status, TransferResult};
+use crate::plan::{self, Planned};
+use crate::{parse_response, run_parallel};
+
+#[derive(ClapArgs, Debug)]
+pub struct DeleteArgs {
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Delete files of the destination directory that the source does not have"
+    )]
+    pub delete: bool,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "delete",
+        help = "Delete without asking for confirmation"
+    )]
+    pub yes: bool,
+}
+
+/// A file to remove so the destination mirrors the source.
+pub enum Deletion {
+    Remote(FileMapping),
+    Local(FileMapping),
+}
+
+fn request_list(remote_dir: &str, cfg: &Config) -> Result<ApiResponse, ClientError> {
+    let mut m = HashMap::new();
+    m.insert("dir_path", remote_dir);
+
+    let url = urls::LIST_URL_V1!(cfg.protocol.data(), cfg.addr);
+    cfg.make_request(url)
+        .json(&m)
+        .send()
+        .map_err(ClientError::from)
+        .and_then(parse_response)
+}
+
+/// Hashes of the files below `remote_dir`, by relative path. A missing dir is empty.
+pub fn list_remote(
+    remote_dir: &str,
+    cfg: &Config,
+) -> Result<BTreeMap<String, String>, ClientError> {
+    match request_list(remote_dir, cfg) {
+        Ok(body) => Ok(hashes(body.files)),
+        Err(ClientError::Api { status: 404, .. }) => Ok(BTreeMap::new()),
+        Err(err) => Err(err),
+    }
+}
+
+pub fn hashes(files: Vec<ApiResponse>) -> BTreeMap<String, String> {
+    files
+        .into_iter()
+        .filter_map(|f| Some((f.path?, f.hash?)))
+        .collect()
+}
+
+fn excludes(mapping: &FileMapping) -> Vec<Pattern> {
+    mapping
+        .excludes
+        .iter()
+        .filter_map(|e| Pattern::new(e).ok())
+        .collect()
+}
+
+fn join(mapping: &FileMapping, relative: &str) -> FileMapping {
+    FileMapping {
+        local: format!("{}/{}", mapping.local.trim_end_matches('/'), relative),
+        remote: format!("{}/{}", mapping.remote.trim_end_matches('/'), relative),
+        excludes: Vec::new(),
+        ..mapping.clone()
+    }
+}
+
+/// One mapping per file below a remote directory, `None` when the remote is
+/// not a directory (or missing) and has to be pulled as a single file.
+pub fn expand_remote_dir(
+    mapping: &FileMapping,
+    cfg: &Config,
+) -> Result<Option<Vec<FileMapping>>, ClientError> {
+    let files = match request_list(&mapping.remote, cfg) {
+        Ok(body) => body.files,
+        Err(ClientError::Api {
+            status: 404 | 409, ..
+        }) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let excludes = excludes(mapping);
+    Ok(Some(
+        files
+            .into_iter()
+            .filter_map(|f| f.path)
+            .filter(|relative| !mapping::is_excluded(&excludes, relative))
+            .map(|relative| join(mapping, &relative))
+            .collect(),
+    ))
+}
+
+/// Fail before anything is transferred when `--delete` would need to list and
+/// delete remote files on a server that cannot.
+pub fn require_remote_delete(args: &DeleteArgs, cfg: &Config) -> anyhow::Result<()> {
+    if args.delete {
+        cfg.require(feature::LIST, "--delete")?;
+        cfg.require(feature::DELETE, "--delete")?;
+    }
+    Ok(())
+}
+
+/// Remote files below a pushed directory that `pushed` does not cover.
+/// Excluded files are never deleted.
+pub fn remote_extras(
+    mapping: &FileMapping,
+    pushed: &[FileMapping],
+    cfg: &Config,
+) -> Result<Vec<Deletion>, ClientError> {
+    let pushed: HashSet<&str> = pushed.iter().map(|m| m.remote.as_str()).collect();
+    let excludes = excludes(mapping);
+    Ok(list_remote(&mapping.remote, cfg)?
+        .into_keys()
+        .filter(|relative| !mapping::is_excluded(&excludes, relative))
+        .map(|relative| join(mapping, &relative))
+        .filter(|m| !pushed.contains(m.remote.as_str()))
+        .map(Deletion::Remote)
+        .collect())
+}
+
+/// Local files below a pulled directory that `pulled` does not cover.
+pub fn local_extras(
+    mapping: &FileMapping,
+    pulled: &[FileMapping],
+) -> anyhow::Result<Vec<Deletion>> {
+    if !Path::new(&mapping.local).is_dir() {
+        return Ok(Vec::new());
+    }
+    let pulled: HashSet<&str> = pulled.iter().map(|m| m.local.as_str()).collect();
+    Ok(mapping::expand_local_dir(mapping)?
+        .into_iter()
+        .filter(|m| !pulled.contains(m.local.as_str()))
+        .map(Deletion::Local)
+        .collect())
+}
+
+/// List the deletions, ask for confirmation unless `--yes`, then delete.
+/// Nothing is deleted when a transfer of this run failed, or with `--dry-run`.
+pub fn delete(
+    args: &DeleteArgs,
+    deletions: &[Deletion],
+    action: &str,
+    cfg: &Config,
+    report: &mut Report,
+) -> anyhow::Result<()> {
+    if deletions.is_empty() {
+        return Ok(());
+    }
+    if cfg.dry_run {
+        let planned: Vec<Planned> = deletions
+            .iter()
+            .map(|d| Planned::Delete(d, action))
+            .collect();
+        plan::report(&planned, cfg, report)?;
+        return Ok(());
+    }
+    let failed = report.results.iter().filter(|r| r.is_failed()).count();
+    if failed > 0 {
+        warn!(
+            "{} transfers failed, skip deleting {} files",
+            failed,
+            deletions.len()
+        );
+        return Ok(());
+    }
+
+    for deletion in deletions.iter() {
+        match deletion {
+            Deletion::Remote(mapping) => info!("delete remote {}", mapping.remote),
+            Deletion::Local(mapping) => info!("delete local {}", mapping.local),
+        }
+    }
+    if !args.yes && !confirm(deletions.len())? {
+        warn!("deletion cancelled");
+        return Ok(());
+    }
+
+    run_parallel(deletions, cfg.jobs, report, |deletion| match deletion {
+        Deletion::Remote(mapping) => delete_remote(mapping, mapping.action_or(action), cfg),
+        Deletion::Local(mapping) => delete_local(mapping),
+    });
+    Ok(())
+}
+
+fn confirm(count: usize) -> anyhow::Result<bool> {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        usage_bail!("stdin is not a terminal, pass --yes to delete without confirmation");
+    }
+    eprint!("Delete {} files? [y/N] ", count);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    stdin.lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+fn delete_remote(mapping: &FileMapping, action: &str, cfg: &Config) -> TransferResult {
+    let start = Instant::now();
+    let mut m = HashMap::new();
+    m.insert("file_path", mapping.remote.as_str());
+    m.insert("action", action);
+
+    let url = urls::DELETE_URL_V1!(cfg.protocol.data(), cfg.addr);
+    let result = cfg
+        .make_request(url)
+        .json(&m)
+        .send()
+        .map_err(ClientError::from)
+        .and_then(parse_response);
+    match result {
+        Err(err) => TransferResult::failed(&mapping.local, &mapping.remote, err),
+        Ok(body) => TransferResult {
+            backup: body.backup,
+            ..TransferResult::new(&mapping.local, &mapping.remote, Status::Deleted)
+        },
+    }
+    .with_duration(start.elapsed())
+}
+
+fn delete_local(mapping: &FileMapping) -> TransferResult {
+    let start = Instant::now();
+    match std::fs::remove_file(&mapping.local) {
+        Err(err) => TransferResult::failed(
+            &mapping.local,
+            &mapping.remote,
+            ClientError::Local(err.to_string()),
+        ),
+        Ok(_) => TransferResult::new(&mapping.local, &mapping.remote, Status::Deleted),
+    }
+    .with_duration(start.elapsed())
+}
@@ -0,0 +1,371 @@
+use std::fmt::Display;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use lib::apis::hooks::HookOutcome;
+use lib::apis::response::ApiError;
+use log::{error, info, warn};
+use reqwest::header::{self, HeaderMap};
+use serde::Serialize;
+
+/// Process exit codes, distinct per failure class so scripts can branch on them.
+pub mod exit_code {
+    pub const SUCCESS: i32 = 0;
+    pub const FAILURE: i32 = 1;
+    pub const USAGE: i32 = 2;
+    pub const PARTIAL: i32 = 3;
+    pub const AUTH: i32 = 4;
+    pub const CONNECTION: i32 = 5;
+    pub const DIFFERENT: i32 = 6;
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Bad command line input that clap cannot catch by itself.
+#[derive(Debug)]
+pub struct UsageError(pub String);
+
+impl Display for UsageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+impl UsageError {
+    pub fn from_err(err: impl Display) -> anyhow::Error {
+        anyhow::Error::new(UsageError(err.to_string()))
+    }
+}
+
+macro_rules! usage_bail {
+    ($($arg:tt)*) => {
+        return Err(anyhow::Error::new($crate::output::UsageError(format!($($arg)*))))
+    };
+}
+pub(crate) use usage_bail;
+
+/// Why a single transfer failed.
+#[derive(Debug, Clone)]
+pub enum ClientError {
+    /// The server could not be reached or the connection broke.
+    Connection(String),
+    /// The server answered with an error envelope, and maybe how long to
+    /// wait before trying again.
+    Api {
+        status: u16,
+        err: ApiError,
+        retry_after: Option<Duration>,
+    },
+    /// Reading or writing the local side failed.
+    Local(String),
+    /// Both sides of a sync changed the file.
+    Conflict(String),
+}
+
+impl ClientError {
+    /// The error a failed response reported, with its `Retry-After` in seconds.
+    pub fn from_response(status: u16, headers: &HeaderMap, text: &str) -> Self {
+        ClientError::Api {
+            status,
+            err: ApiError::from_response(status, text),
+            retry_after: headers
+                .get(header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok())
+                .map(Duration::from_secs),
+        }
+    }
+
+    /// How long the server asked to wait before trying again.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::Api { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// Whether trying again later may succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Connection(_) => true,
+            // Only a server that is overloaded or behind an unready proxy. A
+            // 500 may come after the write and its hooks ran, e.g. when a
+            // rollback failed, and a full disk does not free itself up.
+            Self::Api { status, .. } => matches!(*status, 429 | 502 | 503 | 504),
+            Self::Local(_) | Self::Conflict(_) => false,
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Connection(_) => exit_code::CONNECTION,
+            Self::Api { status: 401, .. } | Self::Api { status: 403, .. } => exit_code::AUTH,
+            _ => exit_code::FAILURE,
+        }
+    }
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Connection(msg) => write!(f, "connection err: {}", msg),
+            Self::Api { status, err, .. } => write!(f, "code={}, {}", status, err),
+            Self::Local(msg) => write!(f, "local err: {}", msg),
+            Self::Conflict(msg) => write!(f, "conflict: {}", msg),
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(err: reqwest::Error) -> Self {
+        match err.status() {
+            Some(status) => ClientError::Api {
+                status: status.as_u16(),
+                err: ApiError::from_response(status.as_u16(), &err.to_string()),
+                retry_after: None,
+            },
+            None if err.is_timeout() => ClientError::Connection(format!("timed out: {}", err)),
+            None => ClientError::Connection(err.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Uploaded,
+    Downloaded,
+    Unchanged,
+    Deleted,
+    WouldCreate,
+    WouldUpdate,
+    WouldDelete,
+    Failed,
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Uploaded => "uploaded",
+            Self::Downloaded => "downloaded",
+            Self::Unchanged => "unchanged",
+            Self::Deleted => "deleted",
+            Self::WouldCreate => "would create",
+            Self::WouldUpdate => "would update",
+            Self::WouldDelete => "would delete",
+            Self::Failed => "failed",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Outcome of one local/remote file pair.
+#[derive(Serialize, Debug)]
+pub struct TransferResult {
+    pub local_path: String,
+    pub remote_path: String,
+    pub status: Status,
+    pub bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backup: Option<String>,
+    pub duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Server hooks run by the write.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hooks: Vec<HookOutcome>,
+    #[serde(skip)]
+    pub exit_code: i32,
+    #[serde(skip)]
+    pub retryable: bool,
+}
+
+impl TransferResult {
+    pub fn new(local_path: &str, remote_path: &str, status: Status) -> Self {
+        TransferResult {
+            local_path: local_path.to_string(),
+            remote_path: remote_path.to_string(),
+            status,
+            bytes: 0,
+            hash: None,
+            backup: None,
+            duration_ms: 0,
+            error: None,
+            hooks: Vec::new(),
+            exit_code: exit_code::SUCCESS,
+            retryable: false,
+        }
+    }
+
+    pub fn failed(local_path: &str, remote_path: &str, err: ClientError) -> Self {
+        let hooks = match &err {
+            ClientError::Api { err, .. } => hooks_for(&err.hooks, remote_path),
+            _ => Vec::new(),
+        };
+        TransferResult {
+            error: Some(err.to_string()),
+            hooks,
+            exit_code: err.exit_code(),
+            retryable: err.is_retryable(),
+            ..Self::new(local_path, remote_path, Status::Failed)
+        }
+    }
+
+    pub fn with_duration(mut self, duration: Duration) -> Self {
+        self.duration_ms = duration.as_millis();
+        self
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.status == Status::Failed
+    }
+}
+
+/// Hooks to show with `remote_path`. A hook that ran for several files of a
+/// batch is shown with the first of them.
+pub fn hooks_for(hooks: &[HookOutcome], remote_path: &str) -> Vec<HookOutcome> {
+    hooks
+        .iter()
+        .filter(|hook| hook.paths.first().is_none_or(|path| path == remote_path))
+        .cloned()
+        .collect()
+}
+
+/// Collects per-file results and prints them in the selected format.
+pub struct Report {
+    format: OutputFormat,
+    pub results: Vec<TransferResult>,
+}
+
+impl Report {
+    pub fn new(format: OutputFormat) -> Self {
+        Report {
+            format,
+            results: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, result: TransferResult) {
+        self.print(&result);
+        self.results.push(result);
+    }
+
+    fn print(&self, result: &TransferResult) {
+        match self.format {
+            OutputFormat::Json => {
+                println!("{}", serde_json::to_string(result).unwrap_or_default());
+            }
+            OutputFormat::Text if result.is_failed() => {
+                error!(
+                    "{} => {}: {}",
+                    result.local_path,
+                    result.remote_path,
+                    result.error.as_deref().unwrap_or_default()
+                );
+                print_hooks(&result.hooks);
+            }
+            OutputFormat::Text => {
+                let mut line = format!(
+                    "{} => {}: {}, bytes={}, {}ms",
+                    result.local_path,
+                    result.remote_path,
+                    result.status,
+                    result.bytes,
+                    result.duration_ms
+                );
+                if let Some(hash) = &result.hash {
+                    line.push_str(&format!(", md5={}", hash));
+                }
+                if let Some(backup) = &result.backup {
+                    line.push_str(&format!(", backup={}", backup));
+                }
+                info!("{}", line);
+                print_hooks(&result.hooks);
+            }
+        }
+    }
+
+    /// Summarise the failures once every result is in, like the old fail list.
+    pub fn finish(&self) {
+        if self.format != OutputFormat::Text || self.results.len() < 2 {
+            return;
+        }
+        let fail_list: Vec<String> = self
+            .results
+            .iter()
+            .filter(|r| r.is_failed())
+            .map(|r| {
+                format!(
+                    "{} => {}",
+                    r.local_path,
+                    r.error.as_deref().unwrap_or_default()
+                )
+            })
+            .collect();
+        if fail_list.is_empty() {
+            info!("all {} files succeeded", self.results.len());
+        } else {
+            error!(
+                "{} of {} files failed:\n{}",
+                fail_list.len(),
+                self.results.len(),
+                fail_list.join("\n")
+            );
+        }
+    }
+
+    /// Exit code summarising every result pushed so far.
+    pub fn exit_code(&self) -> i32 {
+        let failed: Vec<&TransferResult> = self.results.iter().filter(|r| r.is_failed()).collect();
+        if failed.is_empty() {
+            return exit_code::SUCCESS;
+        }
+        if failed.len() < self.results.len() {
+            return exit_code::PARTIAL;
+        }
+        [exit_code::AUTH, exit_code::CONNECTION]
+            .into_iter()
+            .find(|code| failed.iter().any(|r| r.exit_code == *code))
+            .unwrap_or(exit_code::FAILURE)
+    }
+}
+
+fn print_hooks(hooks: &[HookOutcome]) {
+    for hook in hooks.iter() {
+        let mut text = format!(
+            "  {} hook '{}' {}, {}ms",
+            hook.stage.as_str(),
+            hook.command,
+            hook.describe(),
+            hook.duration_ms
+        );
+        for line in hook.output.lines() {
+            text.push_str(&format!("\n    | {}", line));
+        }
+        if hook.ok {
+            info!("{}", text);
+        } else {
+            warn!("{}", text);
+        }
+    }
+}
+
+/// Print a fatal error that happened before or outside any transfer.
+pub fn print_error(format: OutputFormat, err: &anyhow::Error) {
+    match format {
+        OutputFormat::Json => {
+            let value = serde_json::json!({ "status": "error", "error": err.to_string() });
+            println!("{}", value);
+        }
+        OutputFormat::Text => error!("{}", err),
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::fs;
+
+use lib::apis::capabilities::feature;
+use lib::apis::response::{ApiError, ApiResponse, ErrorCode};
+use lib::apis::upload::content_md5;
+use lib::apis::urls;
+use lib::util::schema::parse_mode;
+
+use crate::config::Config;
+use crate::mapping::FileMapping;
+use crate::mirror::Deletion;
+use crate::output::{ClientError, Report, Status, TransferResult};
+use crate::parse_response;
+
+/// What would happen to one file, for `--dry-run`.
+pub enum Planned<'a> {
+    Push(&'a FileMapping, &'a str),
+    Pull(&'a FileMapping),
+    Delete(&'a Deletion, &'a str),
+}
+
+impl Planned<'_> {
+    fn remote(&self) -> &str {
+        match self {
+            Self::Push(mapping, _) | Self::Pull(mapping) => &mapping.remote,
+            Self::Delete(Deletion::Remote(mapping) | Deletion::Local(mapping), _) => {
+                &mapping.remote
+            }
+        }
+    }
+
+    fn local(&self) -> &str {
+        match self {
+            Self::Push(mapping, _) | Self::Pull(mapping) => &mapping.local,
+            Self::Delete(Deletion::Remote(mapping) | Deletion::Local(mapping), _) => &mapping.local,
+        }
+    }
+}
+
+pub fn stat_remote(paths: Vec<&str>, cfg: &Config) -> Result<Vec<ApiResponse>, ClientError> {
+    let mut m = HashMap::new();
+    m.insert("file_paths", paths);
+
+    let url = urls::STAT_URL_V1!(cfg.protocol.data(), cfg.addr);
+    let body = cfg
+        .make_request(url)
+        .json(&m)
+        .send()
+        .map_err(ClientError::from)
+        .and_then(parse_response)?;
+    Ok(body.files)
+}
+
+/// Compare both sides of every planned step and report it, writing nothing.
+pub fn report(planned: &[Planned], cfg: &Config, report: &mut Report) -> anyhow::Result<()> {
+    if planned.is_empty() {
+        return Ok(());
+    }
+    cfg.require(feature::HASHES, "--dry-run")?;
+    let stats = match stat_remote(planned.iter().map(|p| p.remote()).collect(), cfg) {
+        Ok(stats) if stats.len() == planned.len() => stats,
+        Ok(_) => {
+            let err = ApiError::internal("stat answered a wrong number of files");
+            fail_all(
+                planned,
+                ClientError::Api {
+                    status: 500,
+                    err,
+                    retry_after: None,
+                },
+                report,
+            );
+            return Ok(());
+        }
+        Err(err) => {
+            fail_all(planned, err, report);
+            return Ok(());
+        }
+    };
+    for (step, stat) in planned.iter().zip(stats.iter()) {
+        let result = match step {
+            Planned::Push(mapping, action) => push(mapping, mapping.action_or(action), stat),
+            Planned::Pull(mapping) => pull(mapping, stat),
+            Planned::Delete(deletion, action) => delete(deletion, action, stat),
+        };
+        report.push(
+            result.unwrap_or_else(|err| TransferResult::failed(step.local(), step.remote(), err)),
+        );
+    }
+    Ok(())
+}
+
+fn fail_all(planned: &[Planned], err: ClientError, report: &mut Report) {
+    for step in planned.iter() {
+        report.push(TransferResult::failed(
+            step.local(),
+            step.remote(),
+            err.clone(),
+        ));
+    }
+}
+
+/// `None` when the remote file does not exist.
+pub fn existing(stat: &ApiResponse) -> Result<Option<&ApiResponse>, ClientError> {
+    match stat.code {
+        None => Ok(Some(stat)),
+        Some(ErrorCode::NotFound) => Ok(None),
+        Some(code) => Err(ClientError::Api {
+            status: code.status().as_u16(),
+            err: ApiError {
+                code,
+                message: stat.message.clone(),
+                path: stat.path.clone(),
+                hooks: Vec::new(),
+            },
+            retry_after: None,
+        }),
+    }
+}
+
+/// A server that reports the token may not write the path fails the step the
+/// way the real write would. Older servers do not tell, so nothing is checked.
+fn check_writable(stat: &ApiResponse, remote_path: &str) -> Result<(), ClientError> {
+    if stat.writable == Some(false) {
+        return Err(ClientError::Api {
+            status: 403,
+            err: ApiError::new(ErrorCode::PermissionDenied, "token may not write this path")
+                .with_path(remote_path),
+            retry_after: None,
+        });
+    }
+    Ok(())
+}
+
+fn push(
+    mapping: &FileMapping,
+    action: &str,
+    stat: &ApiResponse,
+) -> Result<TransferResult, ClientError> {
+    let content = fs::read(&mapping.local).map_err(|e| ClientError::Local(e.to_string()))?;
+    let hash = content_md5(&content);
+    let result = |status| TransferResult {
+        bytes: content.len() as u64,
+        hash: Some(hash.clone()),
+        ..TransferResult::new(&mapping.local, &mapping.remote, status)
+    };
+
+    let Some(remote) = existing(stat)? else {
+        check_writable(stat, &mapping.remote)?;
+        return Ok(result(Status::WouldCreate));
+    };
+    let same_content = remote.hash.as_deref() == Some(hash.as_str());
+    let same_mode = mapping
+        .mode
+        .as_deref()
+        .is_none_or(|mode| parse_mode(mode) == remote.mode.as_deref().and_then(parse_mode));
+    if !(same_content && same_mode) {
+        check_writable(stat, &mapping.remote)?;
+    }
+    Ok(match (same_content, same_mode) {
+        (true, true) => result(Status::Unchanged),
+        (true, false) => result(Status::WouldUpdate),
+        (false, _) => TransferResult {
+            backup: (action == "safe").then(|| remote.backup.clone()).flatten(),
+            ..result(Status::WouldUpdate)
+        },
+    })
+}
+
+fn pull(mapping: &FileMapping, stat: &ApiResponse) -> Result<TransferResult, ClientError> {
+    let Some(remote) = existing(stat)? else {
+        return Err(ClientError::Api {
+            status: 404,
+            err: ApiError::not_found("not found path").with_path(&mapping.remote),
+            retry_after: None,
+        });
+    };
+    let status = match fs::read(&mapping.local) {
+        Err(_) => Status::WouldCreate,
+        Ok(content) if Some(content_md5(&content)) == remote.hash => Status::Unchanged,
+        Ok(_) => Status::WouldUpdate,
+    };
+    Ok(TransferResult {
+        bytes: remote.bytes.unwrap_or_default(),
+        hash: remote.hash.clone(),
+        ..TransferResult::new(&mapping.local, &mapping.remote, status)
+    })
+}
+
+fn delete(
+    deletion: &Deletion,
+    action: &str,
+    stat: &ApiResponse,
+) -> Result<TransferResult, ClientError> {
+    Ok(match deletion {
+        Deletion::Local(mapping) => {
+            TransferResult::new(&mapping.local, &mapping.remote, Status::WouldDelete)
+        }
+        Deletion::Remote(mapping) => {
+            let remote = existing(stat)?;
+            if remote.is_some() {
+                check_writable(stat, &mapping.remote)?;
+            }
+            TransferResult {
+                backup: remote
+                    .filter(|_| mapping.action_or(action) == "safe")
+                    .and_then(|r| r.backup.clone()),
+                ..TransferResult::new(&mapping.local, &mapping.remote, Status::WouldDelete)
+            }
+        }
+    })
+}
@@ -0,0 +1,120 @@
+use std::io::{self, IsTerminal, Read};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+use crate::output::OutputFormat;
+
+const FILE_TEMPLATE: &str = "{msg:40!} [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec} eta {eta}";
+const STREAM_TEMPLATE: &str = "{msg:40!} {bytes} {bytes_per_sec}";
+const TOTAL_TEMPLATE: &str = "{msg:40!} [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec} eta {eta}";
+
+fn style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .unwrap()
+        .progress_chars("=> ")
+}
+
+/// Progress bars on stderr: one per file in flight, and one for all files
+/// once more than one is transferred.
+pub struct Progress {
+    multi: MultiProgress,
+    total: ProgressBar,
+    files: AtomicUsize,
+}
+
+impl Progress {
+    /// Bars only make sense for a person watching a terminal.
+    pub fn enabled(format: OutputFormat) -> bool {
+        format == OutputFormat::Text && io::stdout().is_terminal() && io::stderr().is_terminal()
+    }
+
+    pub fn new() -> Self {
+        // Drawn only once it is added to `multi`.
+        let total = ProgressBar::with_draw_target(Some(0), ProgressDrawTarget::hidden())
+            .with_style(style(TOTAL_TEMPLATE));
+        Progress {
+            multi: MultiProgress::with_draw_target(ProgressDrawTarget::stderr()),
+            total,
+            files: AtomicUsize::new(0),
+        }
+    }
+
+    /// Log lines are printed above the bars instead of through them.
+    pub fn multi(&self) -> &MultiProgress {
+        &self.multi
+    }
+
+    /// Clear the total bar once every file is done.
+    pub fn finish(&self) {
+        self.total.finish_and_clear();
+    }
+
+    /// A bar for `name`, which has `len` bytes when known.
+    pub fn file(&self, name: &str, len: Option<u64>) -> Arc<FileBar> {
+        let bar = match len {
+            Some(len) => ProgressBar::new(len).with_style(style(FILE_TEMPLATE)),
+            None => ProgressBar::no_length().with_style(style(STREAM_TEMPLATE)),
+        };
+        let bar = self.multi.add(bar.with_message(name.to_string()));
+        self.total.inc_length(len.unwrap_or_default());
+        let files = self.files.fetch_add(1, Ordering::Relaxed) + 1;
+        if files == 2 {
+            self.multi.add(self.total.clone());
+        }
+        self.total.set_message(format!("total of {} files", files));
+        Arc::new(FileBar {
+            bar,
+            total: self.total.clone(),
+        })
+    }
+}
+
+/// The bar of one file, cleared once the last reader using it is dropped.
+pub struct FileBar {
+    bar: ProgressBar,
+    total: ProgressBar,
+}
+
+impl FileBar {
+    /// Count `n` transferred bytes, including ones an earlier attempt sent.
+    pub fn inc(&self, n: u64) {
+        self.bar.inc(n);
+        self.total.inc(n);
+    }
+
+    /// Move the bar to `pos`, e.g. to what the server has after a failed attempt.
+    pub fn reset(&self, pos: u64) {
+        let current = self.bar.position();
+        self.total
+            .set_position((self.total.position() + pos).saturating_sub(current));
+        self.bar.set_position(pos);
+    }
+}
+
+impl Drop for FileBar {
+    fn drop(&mut self) {
+        self.bar.finish_and_clear();
+    }
+}
+
+/// A reader that advances a file bar.
+pub struct ProgressRead<R> {
+    inner: R,
+    bar: Arc<FileBar>,
+}
+
+impl<R: Read> ProgressRead<R> {
+    pub fn new(inner: R, bar: Arc<FileBar>) -> Self {
+        ProgressRead { inner, bar }
+    }
+}
+
+impl<R: Read> Read for ProgressRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bar.inc(n as u64);
+        Ok(n)
+    }
+}
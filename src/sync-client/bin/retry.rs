@@ -0,0 +1,54 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::output::ClientError;
+
+/// How often and how patiently retryable failures are tried again.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Delay before retry `n` (from 1): doubled every retry, capped, and
+    /// jittered down to half so clients failing together do not retry together.
+    fn backoff(&self, n: u32) -> Duration {
+        let delay = self
+            .delay
+            .saturating_mul(2u32.saturating_pow(n - 1))
+            .min(self.max_delay);
+        let random = RandomState::new().hash_one(Instant::now());
+        delay.mul_f64(0.5 + (random % 1000) as f64 / 2000.0)
+    }
+
+    /// Run `op` until it succeeds, fails with an error that is not retryable,
+    /// or runs out of retries. Every operation retried here must be idempotent.
+    pub fn run<T>(
+        &self,
+        what: &str,
+        mut op: impl FnMut() -> Result<T, ClientError>,
+    ) -> Result<T, ClientError> {
+        let mut attempt = 1;
+        loop {
+            debug!("{}: attempt {}/{}", what, attempt, self.retries + 1);
+            match op() {
+                Err(err) if err.is_retryable() && attempt <= self.retries => {
+                    // A 429 says how long the server wants us gone.
+                    let backoff = self
+                        .backoff(attempt)
+                        .max(err.retry_after().unwrap_or_default());
+                    debug!("{}: {}, retry in {:?}", what, err, backoff);
+                    thread::sleep(backoff);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
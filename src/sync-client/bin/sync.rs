@@ -0,0 +1,285 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::Path;
+
+use chrono::Local;
+use clap::{Args as ClapArgs, ValueEnum};
+use lib::apis::capabilities::feature;
+use lib::apis::list::list_files;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::mapping::FileMapping;
+use crate::mirror::{self, hashes, list_remote, DeleteArgs, Deletion};
+use crate::output::{usage_bail, ClientError, Report, Status, TransferResult};
+use crate::plan::{self, Planned};
+use crate::{pull_one, push_one, run_parallel};
+
+#[derive(ClapArgs, Debug)]
+pub struct SyncArgs {
+    #[arg(long)]
+    local_dir: String,
+
+    #[arg(long)]
+    remote_dir: String,
+
+    #[arg(long, help = "safe or force, defaults to the remote's action or safe")]
+    action: Option<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ConflictStrategy::Abort,
+        help = "What to do with files changed on both sides"
+    )]
+    conflict: ConflictStrategy,
+
+    #[arg(long, help = "State file, defaults to [local_dir]/.sync-state.json")]
+    state: Option<String>,
+
+    #[command(flatten)]
+    mirror: DeleteArgs,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictStrategy {
+    /// Transfer nothing while there is a conflict.
+    Abort,
+    /// Pull the remote version and push the local one next to it as `[file].conflict-[time]`.
+    KeepBoth,
+    PreferLocal,
+    PreferRemote,
+}
+
+/// Hashes of the last sync, per path relative to the synced directories.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct State {
+    /// `[addr]:[remote_dir]` the state belongs to.
+    remote: String,
+    files: BTreeMap<String, String>,
+}
+
+impl State {
+    fn load(path: &str, remote: &str) -> anyhow::Result<Self> {
+        if !Path::new(path).exists() {
+            return Ok(State {
+                remote: remote.to_string(),
+                ..Default::default()
+            });
+        }
+        let content = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("read state {} err: {}", path, e))?;
+        let state: State = serde_json::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("parse state {} err: {}", path, e))?;
+        if state.remote != remote {
+            warn!(
+                "state {} belongs to {}, starting over for {}",
+                path, state.remote, remote
+            );
+            return Ok(State {
+                remote: remote.to_string(),
+                ..Default::default()
+            });
+        }
+        Ok(state)
+    }
+
+    /// Written to a temporary file first so an interrupted sync never leaves a torn state.
+    fn save(&self, path: &str) -> anyhow::Result<()> {
+        let tmp = format!("{}.tmp", path);
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)
+            .map_err(|e| anyhow::anyhow!("write state {} err: {}", tmp, e))?;
+        fs::rename(&tmp, path).map_err(|e| anyhow::anyhow!("write state {} err: {}", path, e))
+    }
+}
+
+enum Transfer {
+    Push(FileMapping),
+    Pull(FileMapping),
+}
+
+impl Transfer {
+    fn local(&self) -> &str {
+        match self {
+            Self::Push(mapping) | Self::Pull(mapping) => &mapping.local,
+        }
+    }
+}
+
+/// Compare both sides with the state of the last sync, then push what changed
+/// locally and pull what changed remotely. Deletions are only propagated with `--delete`.
+pub fn sync(args: &SyncArgs, cfg: &Config, report: &mut Report) -> anyhow::Result<()> {
+    let local_dir = args.local_dir.trim_end_matches('/');
+    let remote_dir = args.remote_dir.trim_end_matches('/');
+    if !Path::new(local_dir).is_dir() {
+        usage_bail!("--local-dir {} is not a directory", args.local_dir);
+    }
+    let state_path = args
+        .state
+        .clone()
+        .unwrap_or_else(|| format!("{}/.sync-state.json", local_dir));
+    cfg.require(feature::LIST, "sync")?;
+    mirror::require_remote_delete(&args.mirror, cfg)?;
+    let mut state = State::load(&state_path, &format!("{}:{}", cfg.addr, remote_dir))?;
+
+    let mut local = hashes(
+        list_files(Path::new(local_dir))
+            .map_err(|e| anyhow::anyhow!("list {} err: {}", local_dir, e))?,
+    );
+    if let Ok(relative) = Path::new(&state_path).strip_prefix(local_dir) {
+        local.remove(relative.to_str().unwrap_or_default());
+        local.remove(&format!("{}.tmp", relative.to_str().unwrap_or_default()));
+    }
+    let remote = list_remote(remote_dir, cfg)
+        .map_err(|e| anyhow::anyhow!("list {} err: {}", remote_dir, e))?;
+
+    let mapping = |relative: &str| {
+        FileMapping::new(
+            format!("{}/{}", local_dir, relative),
+            format!("{}/{}", remote_dir, relative),
+        )
+    };
+    let paths: BTreeSet<&String> = local
+        .keys()
+        .chain(remote.keys())
+        .chain(state.files.keys())
+        .collect();
+    let mut transfers = Vec::new();
+    let mut deletions = Vec::new();
+    let mut conflicts = Vec::new();
+    let mut synced = BTreeMap::new();
+    for relative in paths {
+        let (l, r, base) = (
+            local.get(relative),
+            remote.get(relative),
+            state.files.get(relative),
+        );
+        match (l, r) {
+            (None, None) => {}
+            (Some(l), Some(r)) if l == r => {
+                synced.insert(relative.clone(), l.clone());
+            }
+            (Some(_), Some(r)) if Some(r) == base => {
+                transfers.push(Transfer::Push(mapping(relative)))
+            }
+            (Some(l), Some(_)) if Some(l) == base => {
+                transfers.push(Transfer::Pull(mapping(relative)))
+            }
+            (Some(_), Some(_)) => conflicts.push(relative.clone()),
+            // A change wins over a deletion on the other side.
+            (Some(l), None) if Some(l) != base => transfers.push(Transfer::Push(mapping(relative))),
+            (None, Some(r)) if Some(r) != base => transfers.push(Transfer::Pull(mapping(relative))),
+            (Some(l), None) => {
+                if args.mirror.delete {
+                    deletions.push(Deletion::Local(mapping(relative)));
+                } else {
+                    warn!(
+                        "{} was deleted on the remote side, pass --delete to propagate it",
+                        relative
+                    );
+                }
+                synced.insert(relative.clone(), l.clone());
+            }
+            (None, Some(r)) => {
+                if args.mirror.delete {
+                    deletions.push(Deletion::Remote(mapping(relative)));
+                } else {
+                    warn!(
+                        "{} was deleted on the local side, pass --delete to propagate it",
+                        relative
+                    );
+                }
+                synced.insert(relative.clone(), r.clone());
+            }
+        }
+    }
+
+    if !conflicts.is_empty() && args.conflict == ConflictStrategy::Abort {
+        for relative in conflicts.iter() {
+            let mapping = mapping(relative);
+            report.push(TransferResult::failed(
+                &mapping.local,
+                &mapping.remote,
+                ClientError::Conflict(String::from("changed on both sides")),
+            ));
+        }
+        warn!(
+            "{} conflicts, nothing transferred; choose a --conflict strategy",
+            conflicts.len()
+        );
+        return Ok(());
+    }
+    let suffix = Local::now().format("%Y%m%d_%H%M%S").to_string();
+    for relative in conflicts {
+        match args.conflict {
+            ConflictStrategy::Abort => unreachable!(),
+            ConflictStrategy::PreferLocal => transfers.push(Transfer::Push(mapping(&relative))),
+            ConflictStrategy::PreferRemote => transfers.push(Transfer::Pull(mapping(&relative))),
+            ConflictStrategy::KeepBoth => {
+                let mut copy = mapping(&format!("{}.conflict-{}", relative, suffix));
+                if cfg.dry_run {
+                    copy.local = mapping(&relative).local;
+                } else {
+                    fs::copy(&mapping(&relative).local, &copy.local)
+                        .map_err(|e| anyhow::anyhow!("keep {} err: {}", copy.local, e))?;
+                }
+                transfers.push(Transfer::Push(copy));
+                transfers.push(Transfer::Pull(mapping(&relative)));
+            }
+        }
+    }
+
+    if transfers.is_empty() && deletions.is_empty() {
+        info!("{} and {} are in sync", local_dir, remote_dir);
+    }
+    let action = args.action.as_deref().unwrap_or(&cfg.action);
+    if cfg.dry_run {
+        let planned: Vec<Planned> = transfers
+            .iter()
+            .map(|transfer| match transfer {
+                Transfer::Push(mapping) => Planned::Push(mapping, action),
+                Transfer::Pull(mapping) => Planned::Pull(mapping),
+            })
+            .collect();
+        plan::report(&planned, cfg, report)?;
+        return mirror::delete(&args.mirror, &deletions, action, cfg, report);
+    }
+    run_parallel(&transfers, cfg.jobs, report, |transfer| match transfer {
+        Transfer::Push(mapping) => push_one(mapping, action, cfg),
+        Transfer::Pull(mapping) => pull_one(mapping, cfg),
+    });
+
+    // Failed transfers keep their old state so they are compared again next time.
+    let done: HashMap<&str, &TransferResult> = report
+        .results
+        .iter()
+        .filter(|r| !r.is_failed())
+        .map(|r| (r.local_path.as_str(), r))
+        .collect();
+    for transfer in transfers.iter() {
+        let Some(relative) = transfer.local().strip_prefix(&format!("{}/", local_dir)) else {
+            continue;
+        };
+        match done.get(transfer.local()).and_then(|r| r.hash.clone()) {
+            Some(hash) => {
+                synced.insert(relative.to_string(), hash);
+            }
+            None => {
+                if let Some(base) = state.files.get(relative) {
+                    synced.insert(relative.to_string(), base.clone());
+                }
+            }
+        }
+    }
+    mirror::delete(&args.mirror, &deletions, action, cfg, report)?;
+    for result in report.results.iter() {
+        if result.status == Status::Deleted {
+            if let Some(relative) = result.local_path.strip_prefix(&format!("{}/", local_dir)) {
+                synced.remove(relative);
+            }
+        }
+    }
+    state.files = synced;
+    state.save(&state_path)
+}
@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use clap::Args as ClapArgs;
+use glob::Pattern;
+use log::{debug, info, warn};
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use crate::config::Config;
+use crate::mapping::{self, Direction, FileMapping};
+use crate::output::{usage_bail, OutputFormat, Report};
+use crate::{push_one, run_parallel};
+
+#[derive(ClapArgs, Debug)]
+pub struct WatchArgs {
+    #[arg(long, help = "safe or force, defaults to the remote's action or safe")]
+    action: Option<String>,
+
+    #[arg(
+        long,
+        conflicts_with = "manifest",
+        help = "[local1]:[remote1],[local2]:[remote2],..., a local directory is watched recursively"
+    )]
+    file_mappings: Option<String>,
+
+    #[arg(long, help = "TOML manifest declaring [[mapping]] entries")]
+    manifest: Option<String>,
+
+    #[arg(
+        long,
+        default_value_t = 500,
+        help = "Quiet time before changes are pushed"
+    )]
+    debounce_ms: u64,
+
+    #[arg(long, default_value_t = 60, help = "Longest wait between retries")]
+    max_backoff_secs: u64,
+}
+
+/// A watched mapping; `root` is canonical so it matches the event paths.
+struct Target {
+    mapping: FileMapping,
+    root: PathBuf,
+    is_dir: bool,
+    excludes: Vec<Pattern>,
+}
+
+impl Target {
+    fn new(mapping: FileMapping) -> anyhow::Result<Self> {
+        let root = std::fs::canonicalize(&mapping.local)
+            .map_err(|e| anyhow::anyhow!("watch {} err: {}", mapping.local, e))?;
+        Ok(Target {
+            is_dir: root.is_dir(),
+            excludes: mapping
+                .excludes
+                .iter()
+                .filter_map(|e| Pattern::new(e).ok())
+                .collect(),
+            root,
+            mapping,
+        })
+    }
+
+    /// Files are watched through their directory, editors often replace them by rename.
+    fn watch_path(&self) -> (&Path, RecursiveMode) {
+        if self.is_dir {
+            (&self.root, RecursiveMode::Recursive)
+        } else {
+            (
+                self.root.parent().unwrap_or(Path::new("/")),
+                RecursiveMode::NonRecursive,
+            )
+        }
+    }
+
+    /// The single-file mapping to push for a changed `path`, if it belongs to this target.
+    fn resolve(&self, path: &Path) -> Option<FileMapping> {
+        if !self.is_dir {
+            return (path == self.root).then(|| self.mapping.clone());
+        }
+        let relative = path.strip_prefix(&self.root).ok()?.to_str()?;
+        if relative.is_empty() || mapping::is_excluded(&self.excludes, relative) {
+            return None;
+        }
+        Some(FileMapping {
+            local: path.to_string_lossy().to_string(),
+            remote: format!("{}/{}", self.mapping.remote.trim_end_matches('/'), relative),
+            excludes: Vec::new(),
+            ..self.mapping.clone()
+        })
+    }
+}
+
+/// Push changed files until interrupted. Bursts of events are merged until
+/// `debounce_ms` passes without a change; failed pushes that may succeed
+/// later are retried with exponential backoff, e.g. while the server restarts.
+pub fn watch(args: &WatchArgs, cfg: &Config, format: OutputFormat) -> anyhow::Result<i32> {
+    if cfg.dry_run {
+        usage_bail!("watch does not support --dry-run");
+    }
+    let Some(mappings) = mapping::select(
+        args.file_mappings.as_deref(),
+        args.manifest.as_deref(),
+        Direction::Push,
+    )?
+    else {
+        usage_bail!("--file-mappings or --manifest is required");
+    };
+    if mappings.is_empty() {
+        usage_bail!("no file to watch");
+    }
+    let targets = mappings
+        .into_iter()
+        .map(Target::new)
+        .collect::<anyhow::Result<Vec<Target>>>()?;
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    })?;
+    for target in targets.iter() {
+        let (path, mode) = target.watch_path();
+        watcher.watch(path, mode)?;
+        info!(
+            "watching {} => {}",
+            target.mapping.local, target.mapping.remote
+        );
+    }
+
+    let action = args.action.as_deref().unwrap_or(&cfg.action);
+    let debounce = Duration::from_millis(args.debounce_ms);
+    let mut pending: BTreeMap<String, FileMapping> = BTreeMap::new();
+    let mut last_event = Instant::now();
+    let mut retry_at: Option<Instant> = None;
+    let mut failures: u32 = 0;
+
+    loop {
+        let wake_at = match retry_at {
+            Some(retry_at) => retry_at.max(last_event + debounce),
+            None => last_event + debounce,
+        };
+        let timeout = if pending.is_empty() {
+            Duration::from_secs(3600)
+        } else {
+            wake_at.saturating_duration_since(Instant::now())
+        };
+
+        match rx.recv_timeout(timeout) {
+            Ok(Ok(event)) => {
+                if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    continue;
+                }
+                for path in event.paths.iter() {
+                    if let Some(mapping) = targets.iter().find_map(|t| t.resolve(path)) {
+                        debug!("changed: {}", mapping.local);
+                        pending.insert(mapping.local.clone(), mapping);
+                        last_event = Instant::now();
+                    }
+                }
+                continue;
+            }
+            Ok(Err(err)) => {
+                warn!("watch err: {}", err);
+                continue;
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => anyhow::bail!("watcher stopped"),
+        }
+        if pending.is_empty() || Instant::now() < wake_at {
+            continue;
+        }
+
+        // Deleted or replaced-by-directory paths are not pushed.
+        let batch: Vec<FileMapping> = std::mem::take(&mut pending)
+            .into_values()
+            .filter(|m| Path::new(&m.local).is_file())
+            .collect();
+        let mut report = Report::new(format);
+        run_parallel(&batch, cfg.jobs, &mut report, |mapping| {
+            push_one(mapping, action, cfg)
+        });
+
+        let mut batch: BTreeMap<String, FileMapping> =
+            batch.into_iter().map(|m| (m.local.clone(), m)).collect();
+        for result in report.results.iter() {
+            if !result.is_failed() || !result.retryable {
+                continue;
+            }
+            if let Some(mapping) = batch.remove(&result.local_path) {
+                pending.insert(mapping.local.clone(), mapping);
+            }
+        }
+        if pending.is_empty() {
+            failures = 0;
+            retry_at = None;
+        } else {
+            failures += 1;
+            let backoff = Duration::from_secs(
+                2u64.saturating_pow(failures.min(16))
+                    .min(args.max_backoff_secs),
+            );
+            warn!("{} files will be retried in {:?}", pending.len(), backoff);
+            retry_at = Some(Instant::now() + backoff);
+        }
+    }
+}
@@ -1,33 +1,238 @@
-use actix_web::{web, App, HttpServer};
-use clap::Parser;
-use log::info;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::dev::Service;
+use actix_web::http::KeepAlive;
+use actix_web::{http::header, middleware, web, App, HttpServer};
+use clap::{Parser, Subcommand};
+use futures::future::{ready, Either};
+use log::{error, info, LevelFilter};
 
 use lib::apis;
+use lib::apis::metrics::METRICS;
+use lib::apis::rate::{throttle_response, Limiter};
+use lib::apis::response::ApiError;
+use lib::config::{ServerConfig, SharedConfig};
+use lib::util::compress;
 
 #[derive(Parser, Debug)]
 struct Args {
-    #[arg(long, default_value_t = String::from("127.0.0.1"))]
-    host: String,
+    /// Server config file, reloaded on SIGHUP
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Overrides `listen` of the config file
+    #[arg(long)]
+    host: Option<String>,
+
+    /// Overrides `listen` of the config file
+    #[arg(long)]
+    port: Option<u16>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[command(about = "Validate the --config file and exit.")]
+    CheckConfig,
+}
+
+impl Args {
+    fn listen(&self, cfg: &ServerConfig) -> Vec<String> {
+        if self.host.is_none() && self.port.is_none() && !cfg.listen.is_empty() {
+            return cfg.listen.clone();
+        }
+        vec![format!(
+            "{}:{}",
+            self.host.as_deref().unwrap_or("127.0.0.1"),
+            self.port.unwrap_or(9091)
+        )]
+    }
+}
 
-    #[arg(long, default_value_t = 9091)]
-    port: u16,
+fn load_config(args: &Args) -> anyhow::Result<ServerConfig> {
+    match &args.config {
+        Some(path) => ServerConfig::load(path),
+        None => Ok(ServerConfig::default()),
+    }
+}
+
+/// Reload the config on every SIGHUP. In-flight requests keep the snapshot they started with.
+#[cfg(unix)]
+fn watch_reload(shared: Arc<SharedConfig>, listen: Vec<String>) {
+    actix_web::rt::spawn(async move {
+        let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        {
+            Ok(hangup) => hangup,
+            Err(err) => {
+                error!("listen SIGHUP err: {}", err);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            match shared.reload() {
+                Ok(cfg) => {
+                    match cfg.log_level() {
+                        Ok(level) if std::env::var_os("RUST_LOG").is_none() => {
+                            log::set_max_level(level)
+                        }
+                        _ => {}
+                    }
+                    if !cfg.listen.is_empty() && cfg.listen != listen {
+                        log::warn!("listen changed to {:?}, restart to apply it", cfg.listen);
+                    }
+                    info!("config reloaded");
+                }
+                Err(err) => error!("reload config err, keep the old one: {}", err),
+            }
+        }
+    });
+}
+
+/// There is no SIGHUP to reload on, the config is read once.
+#[cfg(not(unix))]
+fn watch_reload(_shared: Arc<SharedConfig>, _listen: Vec<String>) {}
+
+/// Drop expired upload sessions every hour, or sooner with a short expiry.
+fn purge_sessions(shared: Arc<SharedConfig>) {
+    actix_web::rt::spawn(async move {
+        loop {
+            let cfg = shared.current();
+            apis::session::purge_expired(&cfg).await;
+            let period = cfg.sessions.expiry_secs.clamp(1, 3600);
+            tokio::time::sleep(std::time::Duration::from_secs(period)).await;
+        }
+    });
+}
+
+/// The api of protocol version 1, mounted under `/v1` and, for older
+/// clients, without a prefix.
+fn routes_v1(cfg: &mut web::ServiceConfig) {
+    cfg.route("/ping", web::get().to(apis::ping::ping))
+        .route("/upload", web::post().to(apis::upload::upload))
+        .route("/download", web::post().to(apis::download::download_file))
+        .route("/batch", web::post().to(apis::batch::batch_upload))
+        .route("/list", web::post().to(apis::list::list_dir))
+        .route("/delete", web::post().to(apis::delete::delete_file))
+        .route("/stat", web::post().to(apis::stat::stat_files))
+        .route("/backups", web::post().to(apis::backups::list_backups))
+        .route("/sessions", web::post().to(apis::session::create_session))
+        .route(
+            "/sessions/{id}",
+            web::get().to(apis::session::session_status),
+        )
+        .route("/sessions/{id}", web::put().to(apis::session::put_chunk))
+        .route(
+            "/sessions/{id}",
+            web::delete().to(apis::session::delete_session),
+        )
+        .route(
+            "/sessions/{id}/commit",
+            web::post().to(apis::session::commit_session),
+        );
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init();
+    env_logger::builder()
+        .filter_level(LevelFilter::Trace)
+        .parse_default_env()
+        .init();
 
+    std::sync::LazyLock::force(&apis::health::STARTED);
     let args = Args::parse();
+    let cfg = match load_config(&args) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    if let Some(Command::CheckConfig) = args.command {
+        if args.config.is_none() {
+            error!("check-config requires --config");
+            std::process::exit(2);
+        }
+        println!("config ok: {:?}", args.config.unwrap());
+        return Ok(());
+    }
+    if std::env::var_os("RUST_LOG").is_none() {
+        log::set_max_level(cfg.log_level().unwrap_or(LevelFilter::Info));
+    }
     info!("Start sync-server. Args={:?}", args);
 
-    HttpServer::new(|| {
+    let listen = args.listen(&cfg);
+    let shared = Arc::new(SharedConfig::new(args.config.clone(), cfg));
+    watch_reload(shared.clone(), listen.clone());
+    purge_sessions(shared.clone());
+    let roots = shared.current().roots.clone();
+    actix_web::rt::task::spawn_blocking(move || METRICS.scan_backups(&roots));
+
+    // Only read at start-up, like `listen`.
+    let timeouts = shared.current().timeouts.clone();
+    let limiter = Arc::new(Limiter::new(shared.clone()));
+    let data = web::Data::from(shared);
+    let server = HttpServer::new(move || {
         App::new()
-            .route("/ping", web::get().to(apis::ping::ping))
+            .wrap(middleware::Compress::default())
+            .wrap(
+                middleware::DefaultHeaders::new()
+                    .add((header::ACCEPT_ENCODING, compress::SUPPORTED)),
+            )
+            // Outside Compress, so throttles see the compressed bytes on the wire.
+            .wrap_fn({
+                let limiter = limiter.clone();
+                move |mut req, srv| {
+                    if let Err(response) = limiter.admit(&req) {
+                        return Either::Left(ready(Ok(req.into_response(response))));
+                    }
+                    let throttles = limiter.throttle_request(&mut req);
+                    let response = srv.call(req);
+                    Either::Right(async move { Ok(throttle_response(response.await?, throttles)) })
+                }
+            })
+            // Outside the limiter, so its 429 answers are counted too.
+            .wrap_fn(|req, srv| {
+                let started = Instant::now();
+                let method = req.method().clone();
+                let route = req
+                    .match_pattern()
+                    .unwrap_or_else(|| "unmatched".to_string());
+                let response = srv.call(req);
+                async move {
+                    let response = response.await?;
+                    METRICS.observe_request(&route, &method, response.status(), started.elapsed());
+                    Ok(response)
+                }
+            })
+            .app_data(data.clone())
+            .app_data(web::JsonConfig::default().error_handler(|err, _| {
+                ApiError::invalid(format!("invalid json body: {}", err)).into()
+            }))
+            .route(
+                "/capabilities",
+                web::get().to(apis::capabilities::capabilities),
+            )
+            .route("/healthz", web::get().to(apis::health::healthz))
+            .route("/readyz", web::get().to(apis::health::readyz))
+            .route("/metrics", web::get().to(apis::metrics::metrics))
+            .service(web::scope("/v1").configure(routes_v1))
+            // Unversioned routes of clients from before `/v1`.
             .route("/", web::post().to(apis::upload::upload))
-            .route("/upload", web::post().to(apis::upload::upload))
-            .route("/download", web::post().to(apis::download::download_file))
-    })
-    .bind((args.host, args.port))?
-    .run()
-    .await
+            .configure(routes_v1)
+    });
+    let mut server = server
+        .client_request_timeout(Duration::from_secs(timeouts.client_request_secs))
+        .client_disconnect_timeout(Duration::from_secs(timeouts.client_disconnect_secs))
+        .keep_alive(match timeouts.keep_alive_secs {
+            0 => KeepAlive::Disabled,
+            secs => KeepAlive::Timeout(Duration::from_secs(secs)),
+        });
+    for addr in listen.iter() {
+        server = server.bind(addr)?;
+    }
+    server.run().await
 }